@@ -0,0 +1,10 @@
+CREATE TABLE stack (
+    id UUID PRIMARY KEY,
+    kind TEXT NOT NULL,
+    representative_id UUID references image(id)
+);
+
+ALTER TABLE image
+  ADD COLUMN stack_id UUID references stack(id);
+
+CREATE INDEX image_stack_id_idx ON image (stack_id);
@@ -0,0 +1,3 @@
+ALTER TABLE image
+  ADD COLUMN media_kind TEXT NOT NULL DEFAULT 'image',
+  ADD COLUMN duration FLOAT;
@@ -0,0 +1,4 @@
+ALTER TABLE image
+  ADD COLUMN library TEXT;
+
+CREATE INDEX image_library_idx ON image (library);
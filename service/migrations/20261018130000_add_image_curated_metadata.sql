@@ -0,0 +1,4 @@
+ALTER TABLE image
+  ADD COLUMN rating INTEGER,
+  ADD COLUMN caption TEXT,
+  ADD COLUMN sidecar_modified_at TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE image
+  ADD COLUMN latitude DOUBLE PRECISION,
+  ADD COLUMN longitude DOUBLE PRECISION;
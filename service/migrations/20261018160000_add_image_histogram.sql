@@ -0,0 +1 @@
+ALTER TABLE image ADD COLUMN histogram REAL[];
@@ -0,0 +1,17 @@
+ALTER TABLE image ADD COLUMN palette INTEGER[];
+
+-- "Redmean" approximation of perceived distance between two 0xRRGGBB colors, from 0 to about 765
+CREATE FUNCTION color_distance(a INTEGER, b INTEGER) RETURNS DOUBLE PRECISION
+LANGUAGE SQL IMMUTABLE PARALLEL SAFE
+AS $$
+    SELECT sqrt(
+        (2 + r_mean / 256) * power(r1 - r2, 2)
+        + 4 * power(g1 - g2, 2)
+        + (2 + (255 - r_mean) / 256) * power(b1 - b2, 2)
+    )
+    FROM (
+        SELECT (a >> 16) & 255 AS r1, (a >> 8) & 255 AS g1, a & 255 AS b1,
+            (b >> 16) & 255 AS r2, (b >> 8) & 255 AS g2, b & 255 AS b2,
+            (((a >> 16) & 255) + ((b >> 16) & 255)) / 2.0 AS r_mean
+    ) channels;
+$$;
@@ -0,0 +1 @@
+CREATE INDEX image_location_idx ON image (latitude, longitude) WHERE latitude IS NOT NULL;
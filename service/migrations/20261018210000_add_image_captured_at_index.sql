@@ -0,0 +1 @@
+CREATE INDEX image_captured_at_idx ON image (captured_at DESC, id DESC);
@@ -0,0 +1,4 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+CREATE INDEX tag_description_trgm_idx ON tag USING GIN (description gin_trgm_ops);
+CREATE INDEX tag_description_prefix_idx ON tag (description text_pattern_ops);
@@ -0,0 +1,15 @@
+ALTER TABLE tag
+  ADD COLUMN parent_id UUID REFERENCES tag(id),
+  ADD COLUMN path TEXT;
+
+-- Existing tags become roots
+UPDATE tag SET path = description;
+
+ALTER TABLE tag
+  ALTER COLUMN path SET NOT NULL,
+  DROP CONSTRAINT tag_description_key,
+  ADD CONSTRAINT tag_path_key UNIQUE (path),
+  ADD CONSTRAINT tag_parent_description_key UNIQUE NULLS NOT DISTINCT (parent_id, description);
+
+CREATE INDEX tag_parent_id_idx ON tag (parent_id);
+CREATE INDEX tag_path_prefix_idx ON tag (path text_pattern_ops);
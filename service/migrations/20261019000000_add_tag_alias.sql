@@ -0,0 +1,8 @@
+CREATE TABLE tag_alias (
+    id UUID PRIMARY KEY,
+    alias TEXT NOT NULL UNIQUE,
+    tag_id UUID NOT NULL references tag(id) ON DELETE CASCADE,
+    CONSTRAINT tag_alias_lowercase_ck CHECK (alias = LOWER(alias))
+);
+
+CREATE INDEX tag_alias_tag_id_idx ON tag_alias (tag_id);
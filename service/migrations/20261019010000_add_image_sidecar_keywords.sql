@@ -0,0 +1,2 @@
+ALTER TABLE image
+  ADD COLUMN sidecar_keywords TEXT[];
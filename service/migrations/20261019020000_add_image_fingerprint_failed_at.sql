@@ -0,0 +1,2 @@
+ALTER TABLE image
+  ADD COLUMN fingerprint_failed_at TEXT;
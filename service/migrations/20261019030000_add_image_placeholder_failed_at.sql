@@ -0,0 +1,2 @@
+ALTER TABLE image
+  ADD COLUMN placeholder_failed_at TEXT;
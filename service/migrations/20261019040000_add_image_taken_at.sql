@@ -0,0 +1,25 @@
+-- `captured_at` as a timestamp, so date filters can use an index. Casting the text column in
+-- an index isn't possible as the cast depends on the session's settings.
+ALTER TABLE image
+  ADD COLUMN taken_at TIMESTAMPTZ;
+
+UPDATE image SET taken_at = captured_at::timestamptz;
+
+CREATE FUNCTION image_set_taken_at() RETURNS trigger LANGUAGE plpgsql AS $$
+BEGIN
+  NEW.taken_at := NEW.captured_at::timestamptz;
+  RETURN NEW;
+END
+$$;
+
+CREATE TRIGGER image_taken_at
+  BEFORE INSERT OR UPDATE OF captured_at ON image
+  FOR EACH ROW EXECUTE FUNCTION image_set_taken_at();
+
+CREATE INDEX image_taken_at_idx ON image (taken_at);
+
+-- On this day and month/day filters, capture times without a zone are stored as UTC
+CREATE INDEX image_taken_on_day_idx ON image (
+  EXTRACT(MONTH FROM taken_at AT TIME ZONE 'UTC'),
+  EXTRACT(DAY FROM taken_at AT TIME ZONE 'UTC')
+);
@@ -0,0 +1,7 @@
+-- One-time work the service does itself, as it needs the library configuration. A task is
+-- deleted once done.
+CREATE TABLE pending_task (
+  name TEXT PRIMARY KEY
+);
+
+INSERT INTO pending_task (name) VALUES ('nest_folder_tags');
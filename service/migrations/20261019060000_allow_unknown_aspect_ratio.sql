@@ -0,0 +1,2 @@
+-- Videos indexed without ffmpeg have no poster frame to measure until the backfill gets one
+ALTER TABLE image ALTER COLUMN aspect_ratio DROP NOT NULL;
@@ -83,6 +83,37 @@ impl FromRequestParts<AppState> for AuthenticatedAccount {
     }
 }
 
+/// An authenticated account listed in the comma-separated `ADMIN_USERS`
+///
+/// Without `ADMIN_USERS` nobody is an admin.
+pub struct AdminAccount {
+    pub username: String,
+}
+
+impl FromRequestParts<AppState> for AdminAccount {
+    type Rejection = AppError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let account = AuthenticatedAccount::from_request_parts(parts, state).await?;
+
+        let admins = std::env::var("ADMIN_USERS").unwrap_or_default();
+        if !admins
+            .split(',')
+            .any(|admin| admin.trim() == account.username)
+        {
+            error!(message = "Account is not an admin");
+            return Err(AppError::Status(StatusCode::FORBIDDEN));
+        }
+
+        Ok(AdminAccount {
+            username: account.username,
+        })
+    }
+}
+
 #[tracing::instrument(skip_all, fields( account = %account.username ))]
 pub async fn login(
     jar: CookieJar,
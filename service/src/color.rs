@@ -0,0 +1,173 @@
+use axum::http::StatusCode;
+use image::DynamicImage;
+
+use crate::error::AppError;
+
+const PALETTE_SIZE: usize = 5;
+const KMEANS_ITERATIONS: usize = 10;
+/// Colors covering less of the image are specks, not what the photo looks like
+const MIN_SHARE: f32 = 0.05;
+
+/// Tolerance for `color:#rrggbb` without an explicit `~tolerance`
+const DEFAULT_HEX_TOLERANCE: f32 = 60.0;
+/// Named colors match anything close to one of a few typical shades
+const NAMED_TOLERANCE: f32 = 90.0;
+
+const NAMED_COLORS: &[(&str, &[i32])] = &[
+    ("red", &[0xff0000, 0xc81e1e, 0xe74c3c, 0x8b0000, 0xff6b6b]),
+    (
+        "orange",
+        &[0xffa500, 0xe67e22, 0xf39c12, 0xff8c00, 0xd35400],
+    ),
+    (
+        "yellow",
+        &[0xffff00, 0xf1c40f, 0xffd700, 0xfff176, 0xc9a600],
+    ),
+    (
+        "green",
+        &[
+            0x00ff00, 0x00c800, 0x228b22, 0x2ecc71, 0x556b2f, 0x1e5631, 0x9acd32,
+        ],
+    ),
+    ("teal", &[0x008080, 0x20b2aa, 0x16a085, 0x1abc9c]),
+    (
+        "blue",
+        &[
+            0x0000ff, 0x1e1ec8, 0x4169e1, 0x2980b9, 0x1f3a93, 0x87ceeb, 0x0b1d51,
+        ],
+    ),
+    (
+        "purple",
+        &[0x800080, 0x8e44ad, 0x9b59b6, 0x4b0082, 0xb39ddb],
+    ),
+    ("pink", &[0xff69b4, 0xff1493, 0xffc0cb, 0xe91e63]),
+    ("brown", &[0x8b4513, 0xa0522d, 0x5d4037, 0xc19a6b]),
+    ("black", &[0x000000, 0x1a1a1a]),
+    ("gray", &[0x808080, 0xa9a9a9, 0x505050, 0xc0c0c0]),
+    ("grey", &[0x808080, 0xa9a9a9, 0x505050, 0xc0c0c0]),
+    ("white", &[0xffffff, 0xf0f0f0]),
+];
+
+/// A `color:` search filter, matching images with a dominant color close to one of `shades`
+#[derive(Debug, Clone)]
+pub struct ColorFilter {
+    /// `0xRRGGBB`, as stored in `image.palette`
+    pub shades: Vec<i32>,
+    /// Maximum distance as computed by the `color_distance` SQL function
+    pub tolerance: f32,
+}
+
+/// Parse the value of a `color:` filter: a name like `blue`, or `#ff8800` with an optional
+/// `~tolerance` like `#ff8800~40`
+pub fn parse_color_filter(value: &str) -> Result<ColorFilter, AppError> {
+    let invalid = || {
+        AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "invalid color '{}', expected a name like 'blue' or a hex value like '#ff8800~40'",
+                value
+            ),
+        )
+    };
+
+    let value = value.to_lowercase();
+
+    if let Some((_, shades)) = NAMED_COLORS.iter().find(|(name, _)| *name == value) {
+        return Ok(ColorFilter {
+            shades: shades.to_vec(),
+            tolerance: NAMED_TOLERANCE,
+        });
+    }
+
+    let hex = value.strip_prefix('#').ok_or_else(invalid)?;
+    let (hex, tolerance) = match hex.split_once('~') {
+        Some((hex, tolerance)) => (hex, tolerance.parse().map_err(|_| invalid())?),
+        None => (hex, DEFAULT_HEX_TOLERANCE),
+    };
+    if hex.len() != 6 {
+        return Err(invalid());
+    }
+    let color = i32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+
+    Ok(ColorFilter {
+        shades: vec![color],
+        tolerance,
+    })
+}
+
+/// Up to five dominant colors as `0xRRGGBB`, the most prominent first
+///
+/// Clusters the pixels of a thumbnail with k-means, seeded with colors spread evenly across
+/// the brightness range so the result doesn't change between runs.
+pub fn dominant_colors(image: &DynamicImage) -> Vec<i32> {
+    let thumbnail = image.thumbnail(64, 64).to_rgb8();
+    let mut pixels = thumbnail
+        .pixels()
+        .map(|pixel| pixel.0.map(|c| c as f32))
+        .collect::<Vec<_>>();
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    pixels.sort_by(|a, b| luma(a).total_cmp(&luma(b)));
+    let mut centers = (0..PALETTE_SIZE)
+        .map(|i| pixels[(2 * i + 1) * pixels.len() / (2 * PALETTE_SIZE)])
+        .collect::<Vec<_>>();
+
+    let mut assignments = vec![0; pixels.len()];
+    for _ in 0..KMEANS_ITERATIONS {
+        for (pixel, assignment) in pixels.iter().zip(assignments.iter_mut()) {
+            *assignment = (0..centers.len())
+                .min_by(|&a, &b| {
+                    squared_distance(pixel, &centers[a])
+                        .total_cmp(&squared_distance(pixel, &centers[b]))
+                })
+                .unwrap_or_default();
+        }
+
+        let mut sums = vec![([0f32; 3], 0usize); centers.len()];
+        for (pixel, &assignment) in pixels.iter().zip(&assignments) {
+            let (sum, count) = &mut sums[assignment];
+            for channel in 0..3 {
+                sum[channel] += pixel[channel];
+            }
+            *count += 1;
+        }
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0 {
+                *center = sum.map(|c| c / count as f32);
+            }
+        }
+    }
+
+    let mut counts = vec![0usize; centers.len()];
+    for &assignment in &assignments {
+        counts[assignment] += 1;
+    }
+
+    let mut palette = centers
+        .into_iter()
+        .zip(counts)
+        .filter(|(_, count)| *count as f32 / pixels.len() as f32 >= MIN_SHARE)
+        .collect::<Vec<_>>();
+    palette.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+
+    let mut colors = palette
+        .into_iter()
+        .map(|(center, _)| {
+            let [r, g, b] = center.map(|c| c.round().clamp(0.0, 255.0) as i32);
+            (r << 16) | (g << 8) | b
+        })
+        .collect::<Vec<_>>();
+    colors.dedup();
+
+    colors
+}
+
+fn luma(pixel: &[f32; 3]) -> f32 {
+    0.299 * pixel[0] + 0.587 * pixel[1] + 0.114 * pixel[2]
+}
+
+fn squared_distance(a: &[f32; 3], b: &[f32; 3]) -> f32 {
+    (0..3).map(|c| (a[c] - b[c]).powi(2)).sum()
+}
@@ -0,0 +1,29 @@
+use axum::http::StatusCode;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::error::AppError;
+
+/// Encode the position after the last returned item as an opaque string
+///
+/// Clients pass it back unchanged, so it's only hex encoded JSON.
+pub fn encode_cursor<T: Serialize>(position: &T) -> Result<String, AppError> {
+    Ok(serde_json::to_vec(position)?
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+pub fn decode_cursor<T: DeserializeOwned>(cursor: &str) -> Result<T, AppError> {
+    let invalid = || AppError::Text(StatusCode::BAD_REQUEST, "invalid cursor".to_string());
+
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| invalid())?;
+
+    serde_json::from_slice(&bytes).map_err(|_| invalid())
+}
@@ -0,0 +1,387 @@
+use std::{
+    fs,
+    io::{BufReader, Cursor, Read},
+    path::Path,
+    process::Command,
+};
+
+use axum::http::StatusCode;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use tracing::{info, warn};
+
+use crate::{error::AppError, video::poster_frame};
+
+const HEIF_EXTENSIONS: &[&str] = &["heic", "heif", "hif"];
+const RAW_EXTENSIONS: &[&str] = &[
+    "cr2", "cr3", "crw", "nef", "nrw", "arw", "srf", "sr2", "dng", "raf", "orf", "rw2", "pef",
+    "srw", "3fr", "iiq", "erf",
+];
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "m4v", "mov", "avi", "mkv", "webm", "3gp", "mts", "m2ts", "mpg", "mpeg", "wmv",
+];
+// ISO base media files announce still images with these brands, anything else is a video
+const STILL_IMAGE_BRANDS: &[&[u8]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFormat {
+    /// Anything the `image` crate can decode by itself
+    Standard,
+    Heif,
+    Raw,
+    Video,
+}
+
+pub fn source_format(path: &Path) -> SourceFormat {
+    match extension_format(path) {
+        SourceFormat::Standard if has_video_signature(path) => SourceFormat::Video,
+        format => format,
+    }
+}
+
+/// The format told by the extension alone, without opening the file
+///
+/// Videos without a telling extension come out as `Standard`, use the stored `media_kind` for
+/// indexed files.
+pub fn extension_format(path: &Path) -> SourceFormat {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+
+    if HEIF_EXTENSIONS.contains(&extension.as_str()) {
+        SourceFormat::Heif
+    } else if RAW_EXTENSIONS.contains(&extension.as_str()) {
+        SourceFormat::Raw
+    } else if VIDEO_EXTENSIONS.contains(&extension.as_str()) {
+        SourceFormat::Video
+    } else {
+        SourceFormat::Standard
+    }
+}
+
+/// Recognize video containers by their magic bytes, for files without a telling extension
+fn has_video_signature(path: &Path) -> bool {
+    let mut header = [0u8; 12];
+    let Ok(mut file) = fs::File::open(path) else {
+        return false;
+    };
+    if file.read_exact(&mut header).is_err() {
+        return false;
+    }
+
+    is_video_header(&header)
+}
+
+fn is_video_header(header: &[u8; 12]) -> bool {
+    let iso_base_media = &header[4..8] == b"ftyp" && !STILL_IMAGE_BRANDS.contains(&&header[8..12]);
+    let matroska = header[..4] == [0x1A, 0x45, 0xDF, 0xA3];
+    let avi = &header[..4] == b"RIFF" && &header[8..12] == b"AVI ";
+
+    iso_base_media || matroska || avi
+}
+
+/// Decode the file at `path` into pixels, whatever format it is stored in
+pub fn decode_image(path: &Path) -> Result<DynamicImage, AppError> {
+    match source_format(path) {
+        SourceFormat::Standard => Ok(ImageReader::open(path)?.with_guessed_format()?.decode()?),
+        SourceFormat::Heif => decode_heif(path),
+        SourceFormat::Raw => decode_raw(path),
+        SourceFormat::Video => poster_frame(path),
+    }
+}
+
+/// `decode_image` on the blocking thread pool, decoding and running ffmpeg take a while
+pub async fn decode_image_blocking(path: &Path) -> Result<DynamicImage, AppError> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || decode_image(&path)).await?
+}
+
+/// Get the raw EXIF block of the file at `path`
+pub fn read_exif(path: &Path) -> Result<Option<Vec<u8>>, AppError> {
+    let format = source_format(path);
+    match format {
+        SourceFormat::Standard => {
+            let image = ImageReader::open(path)?.with_guessed_format()?;
+            Ok(image.into_decoder()?.exif_metadata()?)
+        }
+        SourceFormat::Video => Ok(None),
+        SourceFormat::Heif | SourceFormat::Raw => {
+            let mut reader = BufReader::new(fs::File::open(path)?);
+            match exif::Reader::new().read_from_container(&mut reader) {
+                Ok(exif) => Ok(Some(exif.buf().to_vec())),
+                // Containers kamadak-exif doesn't understand (e.g. CR3) usually carry
+                // the EXIF block in their embedded preview as well
+                Err(_) if format == SourceFormat::Raw => {
+                    let data = fs::read(path)?;
+                    let Some(preview) = embedded_preview(&data) else {
+                        return Ok(None);
+                    };
+                    let image = ImageReader::new(Cursor::new(preview)).with_guessed_format()?;
+                    let exif = image.into_decoder()?.exif_metadata()?;
+                    Ok(exif)
+                }
+                // Phones and editors write HEIF files without EXIF too, they are still images
+                Err(error) => {
+                    info!(message = "HEIF file has no readable EXIF", path = %path.display(), %error);
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "heif")]
+fn decode_heif(path: &Path) -> Result<DynamicImage, AppError> {
+    use image::RgbImage;
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let lib_heif = LibHeif::new();
+    let path = path.to_str().ok_or(AppError::Text(
+        StatusCode::BAD_REQUEST,
+        "path is not unicode".to_string(),
+    ))?;
+    let context = HeifContext::read_from_file(path)?;
+    let handle = context.primary_image_handle()?;
+    let image = lib_heif.decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)?;
+
+    let planes = image.planes();
+    let Some(plane) = planes.interleaved else {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "HEIF image has no interleaved plane".to_string(),
+        ));
+    };
+
+    // Rows in the plane may be padded beyond `width * 3` bytes
+    let row_length = plane.width as usize * 3;
+    let mut pixels = Vec::with_capacity(row_length * plane.height as usize);
+    for row in plane.data.chunks(plane.stride).take(plane.height as usize) {
+        pixels.extend_from_slice(&row[..row_length]);
+    }
+
+    let image = RgbImage::from_raw(plane.width, plane.height, pixels).ok_or(AppError::Text(
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "HEIF image has unexpected dimensions".to_string(),
+    ))?;
+
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+#[cfg(not(feature = "heif"))]
+fn decode_heif(_path: &Path) -> Result<DynamicImage, AppError> {
+    Err(AppError::Text(
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "HEIF support is not enabled in this build".to_string(),
+    ))
+}
+
+/// Decode a camera RAW file
+///
+/// If `RAW_DEMOSAIC_COMMAND` is set (e.g. `dcraw -c -w -T`), the command is run with the
+/// file path appended and whatever it writes to stdout is decoded. Otherwise, or if the
+/// command fails, the full-size JPEG preview embedded by the camera is used.
+fn decode_raw(path: &Path) -> Result<DynamicImage, AppError> {
+    if let Ok(command) = std::env::var("RAW_DEMOSAIC_COMMAND") {
+        match demosaic(&command, path) {
+            Ok(image) => return Ok(image),
+            Err(error) => {
+                warn!(message = "demosaicing RAW file failed, falling back to embedded preview", file = ?path, %error)
+            }
+        }
+    }
+
+    let data = fs::read(path)?;
+    let Some(preview) = embedded_preview(&data) else {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "RAW file has no decodable embedded preview".to_string(),
+        ));
+    };
+
+    Ok(ImageReader::new(Cursor::new(preview))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+fn demosaic(command: &str, path: &Path) -> Result<DynamicImage, AppError> {
+    let mut parts = command.split_whitespace();
+    let Some(program) = parts.next() else {
+        return Err(AppError::Text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "RAW_DEMOSAIC_COMMAND is empty".to_string(),
+        ));
+    };
+
+    info!(message = "demosaicing RAW file", file = ?path);
+    let output = Command::new(program).args(parts).arg(path).output()?;
+
+    if !output.status.success() {
+        return Err(AppError::Text(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{} exited with {}", program, output.status),
+        ));
+    }
+
+    Ok(ImageReader::new(Cursor::new(output.stdout))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+/// Find the largest decodable JPEG stream embedded in `data`
+///
+/// Cameras store a full-size JPEG preview next to the sensor data in pretty much every
+/// RAW format, so scanning for JPEG streams avoids parsing each vendor's container.
+fn embedded_preview(data: &[u8]) -> Option<&[u8]> {
+    let mut candidates = Vec::new();
+
+    let mut position = 0;
+    while position + 3 < data.len() {
+        if data[position] == 0xFF && data[position + 1] == 0xD8 && data[position + 2] == 0xFF {
+            if let Some(end) = jpeg_end(data, position) {
+                candidates.push(&data[position..end]);
+                // Skip nested streams such as the EXIF thumbnail inside the preview
+                position = end;
+                continue;
+            }
+        }
+        position += 1;
+    }
+
+    // Lossless JPEG sensor data (e.g. CR2) looks like a JPEG as well but can't be decoded
+    candidates.sort_by_key(|c| std::cmp::Reverse(c.len()));
+    candidates.into_iter().find(|candidate| {
+        ImageReader::new(Cursor::new(candidate))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.into_dimensions().ok())
+            .is_some_and(|(width, height)| width > 0 && height > 0)
+    })
+}
+
+/// Walk the marker segments of the JPEG starting at `start` and return its end offset
+fn jpeg_end(data: &[u8], start: usize) -> Option<usize> {
+    let segment_length = |at: usize| -> Option<usize> {
+        Some(u16::from_be_bytes([*data.get(at + 2)?, *data.get(at + 3)?]) as usize)
+    };
+
+    let mut position = start + 2;
+    loop {
+        if *data.get(position)? != 0xFF {
+            return None;
+        }
+        match *data.get(position + 1)? {
+            // Fill byte
+            0xFF => position += 1,
+            // End of image
+            0xD9 => return Some(position + 2),
+            // Markers without a payload
+            0x01 | 0xD0..=0xD7 => position += 2,
+            // Start of scan, followed by entropy-coded data up to the next marker
+            0xDA => {
+                position += 2 + segment_length(position)?;
+                while position + 1 < data.len() {
+                    let next = data[position + 1];
+                    if data[position] == 0xFF && next != 0x00 && !(0xD0..=0xD7).contains(&next) {
+                        break;
+                    }
+                    position += 1;
+                }
+            }
+            0xC0..=0xFE => position += 2 + segment_length(position)?,
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+
+    use super::*;
+
+    fn jpeg(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        let image = RgbImage::from_pixel(width, height, image::Rgb([200, 120, 40]));
+        JpegEncoder::new(&mut data).encode_image(&image).unwrap();
+        data
+    }
+
+    #[test]
+    fn jpeg_end_skips_markers_inside_segments() {
+        let mut data = jpeg(8, 8);
+        // An APP1 segment right after SOI whose payload looks like an EOI and a nested SOI
+        let payload = [0xFF, 0xD9, 0xFF, 0xD8, 0xFF, 0xE0];
+        let mut segment = vec![0xFF, 0xE1, 0x00, payload.len() as u8 + 2];
+        segment.extend_from_slice(&payload);
+        data.splice(2..2, segment);
+
+        assert_eq!(jpeg_end(&data, 0), Some(data.len()));
+    }
+
+    #[test]
+    fn jpeg_end_rejects_truncated_streams() {
+        let data = jpeg(8, 8);
+
+        assert_eq!(jpeg_end(&data[..data.len() - 2], 0), None);
+        assert_eq!(jpeg_end(&data[..data.len() / 2], 0), None);
+    }
+
+    #[test]
+    fn embedded_preview_takes_the_largest_decodable_stream() {
+        let thumbnail = jpeg(8, 8);
+        let preview = jpeg(64, 48);
+        let mut data = b"RAW header".to_vec();
+        data.extend_from_slice(&thumbnail);
+        data.extend_from_slice(&[0x00, 0xFF, 0xD8, 0x12, 0x34]);
+        data.extend_from_slice(&preview);
+        data.extend_from_slice(b"sensor data");
+
+        assert_eq!(embedded_preview(&data), Some(preview.as_slice()));
+    }
+
+    #[test]
+    fn embedded_preview_skips_truncated_previews() {
+        let thumbnail = jpeg(8, 8);
+        let preview = jpeg(64, 48);
+        let mut data = thumbnail.clone();
+        data.extend_from_slice(&preview[..preview.len() / 2]);
+
+        assert_eq!(embedded_preview(&data), Some(thumbnail.as_slice()));
+        assert_eq!(embedded_preview(&preview[..preview.len() / 2]), None);
+    }
+
+    #[test]
+    fn video_headers() {
+        assert!(is_video_header(b"\0\0\0\x18ftypisom"));
+        assert!(is_video_header(b"\0\0\0\x14ftypqt  "));
+        assert!(is_video_header(b"RIFF\0\0\0\0AVI "));
+        assert!(is_video_header(&[
+            0x1A, 0x45, 0xDF, 0xA3, 0, 0, 0, 0, 0, 0, 0, 0
+        ]));
+
+        assert!(!is_video_header(b"\0\0\0\x18ftypheic"));
+        assert!(!is_video_header(b"\0\0\0\x18ftypavif"));
+        assert!(!is_video_header(b"RIFF\0\0\0\0WEBP"));
+        assert!(!is_video_header(&[
+            0xFF, 0xD8, 0xFF, 0xE0, 0, 0, 0, 0, 0, 0, 0, 0
+        ]));
+    }
+
+    #[test]
+    fn video_behind_an_image_extension() {
+        let directory = std::env::temp_dir().join(format!("reflective-{}", uuid::Uuid::now_v7()));
+        fs::create_dir_all(&directory).unwrap();
+        let video = directory.join("clip.jpg");
+        fs::write(&video, b"\0\0\0\x18ftypmp42\0\0\0\0").unwrap();
+        let still = directory.join("photo.jpg");
+        fs::write(&still, jpeg(8, 8)).unwrap();
+
+        assert_eq!(source_format(&video), SourceFormat::Video);
+        assert_eq!(source_format(&still), SourceFormat::Standard);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fs, path};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, FromRow};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{auth::AuthenticatedAccount, error::AppError, fingerprint::hamming_distance, AppState};
+
+const DEFAULT_DISTANCE: u32 = 6;
+/// Beyond this, unrelated photos with similar composition start to cluster
+const MAX_DISTANCE: u32 = 16;
+
+#[derive(Deserialize, Debug)]
+pub struct DuplicatesParams {
+    /// Maximum number of differing hash bits for two images to count as duplicates
+    distance: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateImage {
+    id: Uuid,
+    path: String,
+    library: Option<String>,
+    captured_at: String,
+    width: i32,
+    height: i32,
+    /// `None` if the file is no longer on disk
+    file_size: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicateCluster {
+    /// Largest resolution first, then largest file, as that's usually the copy to keep
+    images: Vec<DuplicateImage>,
+}
+
+#[derive(Serialize)]
+pub struct DuplicatesResponse {
+    clusters: Vec<DuplicateCluster>,
+}
+
+#[derive(FromRow)]
+struct Candidate {
+    id: Uuid,
+    filename: String,
+    library: Option<String>,
+    captured_at: String,
+    dhash: Option<i64>,
+    stack_id: Option<Uuid>,
+    width: i32,
+    height: i32,
+}
+
+/// List clusters of images whose difference hashes are within `distance` bits of each other
+///
+/// Members of the same stack, e.g. RAW+JPEG pairs, are duplicates on purpose and not reported.
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    distance = ?params.distance,
+))]
+pub async fn get_duplicates(
+    account: AuthenticatedAccount,
+    params: Query<DuplicatesParams>,
+    State(state): State<AppState>,
+) -> Result<Json<DuplicatesResponse>, AppError> {
+    let distance = params.distance.unwrap_or(DEFAULT_DISTANCE);
+    if distance > MAX_DISTANCE {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("distance must be at most {}", MAX_DISTANCE),
+        ));
+    }
+
+    let candidates = query_as!(
+        Candidate,
+        "
+            SELECT image.id, image.filename, image.library, image.captured_at, image.dhash, image.stack_id,
+                variant.width, variant.height
+            FROM image
+            INNER JOIN variant ON variant.image_id = image.id AND variant.quality = 'original'
+            WHERE image.dhash IS NOT NULL;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut tree = BkTree::default();
+    for (index, candidate) in candidates.iter().enumerate() {
+        tree.insert(candidate.dhash.unwrap_or_default(), index);
+    }
+
+    let mut clusters = DisjointSet::new(candidates.len());
+    let mut matches = vec![];
+    for (index, candidate) in candidates.iter().enumerate() {
+        matches.clear();
+        tree.find(candidate.dhash.unwrap_or_default(), distance, &mut matches);
+
+        for &other in &matches {
+            let same_stack =
+                candidate.stack_id.is_some() && candidate.stack_id == candidates[other].stack_id;
+            if other != index && !same_stack {
+                clusters.union(index, other);
+            }
+        }
+    }
+
+    let mut grouped: Vec<Vec<usize>> = vec![vec![]; candidates.len()];
+    for index in 0..candidates.len() {
+        grouped[clusters.root(index)].push(index);
+    }
+
+    let mut clusters = grouped
+        .into_iter()
+        .filter(|members| members.len() > 1)
+        .map(|members| {
+            let mut images = members
+                .into_iter()
+                .map(|index| {
+                    let candidate = &candidates[index];
+                    DuplicateImage {
+                        id: candidate.id,
+                        path: candidate.filename.clone(),
+                        library: candidate.library.clone(),
+                        captured_at: candidate.captured_at.clone(),
+                        width: candidate.width,
+                        height: candidate.height,
+                        file_size: fs::metadata(path::Path::new(&candidate.filename))
+                            .ok()
+                            .map(|m| m.len()),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            images.sort_by_key(|image| {
+                std::cmp::Reverse((
+                    image.width as i64 * image.height as i64,
+                    image.file_size.unwrap_or_default(),
+                ))
+            });
+
+            DuplicateCluster { images }
+        })
+        .collect::<Vec<_>>();
+
+    clusters.sort_by_key(|cluster| std::cmp::Reverse(cluster.images.len()));
+
+    info!(message = "found duplicates", clusters = clusters.len());
+
+    Ok(Json(DuplicatesResponse { clusters }))
+}
+
+/// Burkhard-Keller tree for finding hashes within a Hamming distance without comparing all pairs
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: i64,
+    index: usize,
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: i64, index: usize) {
+        let new = self.nodes.len();
+        self.nodes.push(BkNode {
+            hash,
+            index,
+            children: vec![],
+        });
+        if new == 0 {
+            return;
+        }
+
+        let mut node = 0;
+        loop {
+            let distance = hamming_distance(self.nodes[node].hash, hash);
+            match self.nodes[node]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+            {
+                Some(&(_, child)) => node = child,
+                None => {
+                    self.nodes[node].children.push((distance, new));
+                    return;
+                }
+            }
+        }
+    }
+
+    fn find(&self, hash: i64, max_distance: u32, found: &mut Vec<usize>) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let mut pending = vec![0];
+        while let Some(node) = pending.pop() {
+            let node = &self.nodes[node];
+            let distance = hamming_distance(node.hash, hash);
+            if distance <= max_distance {
+                found.push(node.index);
+            }
+
+            // By the triangle inequality, only these subtrees can hold matches
+            for &(child_distance, child) in &node.children {
+                if child_distance.abs_diff(distance) <= max_distance {
+                    pending.push(child);
+                }
+            }
+        }
+    }
+}
+
+struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> Self {
+        DisjointSet {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn root(&mut self, mut index: usize) -> usize {
+        while self.parents[index] != index {
+            self.parents[index] = self.parents[self.parents[index]];
+            index = self.parents[index];
+        }
+        index
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            self.parents[b] = a;
+        }
+    }
+}
@@ -32,6 +32,13 @@ pub enum AppError {
     DateParseError(#[from] jiff::Error),
     #[error("Uuid parse error {0}")]
     UuidParseError(#[from] uuid::Error),
+    #[error("XML error {0}")]
+    XmlError(#[from] quick_xml::Error),
+    #[error("Task error {0}")]
+    TaskError(#[from] tokio::task::JoinError),
+    #[cfg(feature = "heif")]
+    #[error("HEIF error {0}")]
+    HeifError(#[from] libheif_rs::HeifError),
 }
 
 impl IntoResponse for AppError {
@@ -66,6 +73,14 @@ impl IntoResponse for AppError {
             AppError::UuidParseError(error) => {
                 (StatusCode::BAD_REQUEST, error.to_string()).into_response()
             }
+            AppError::XmlError(error) => {
+                (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+            }
+            AppError::TaskError(_) => (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+            #[cfg(feature = "heif")]
+            AppError::HeifError(error) => {
+                (StatusCode::BAD_REQUEST, error.to_string()).into_response()
+            }
         }
     }
 }
@@ -0,0 +1,146 @@
+use serde::Serialize;
+use sqlx::{FromRow, Pool, Postgres, QueryBuilder};
+
+use crate::error::AppError;
+
+/// Most frequent values returned for tags, cameras, lenses and places
+const MAX_FACET_VALUES: i64 = 50;
+
+#[derive(Serialize)]
+pub struct FacetCount {
+    value: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+pub struct PlaceCount {
+    country: String,
+    region: Option<String>,
+    city: Option<String>,
+    count: i64,
+}
+
+/// Counts of the values found among the images matching a search
+#[derive(Serialize, Default)]
+pub struct Facets {
+    /// Most frequent first, tags count the images of their descendants too
+    tags: Vec<FacetCount>,
+    /// Newest first, as `2023`
+    years: Vec<FacetCount>,
+    /// Newest first, as `2023-12`
+    months: Vec<FacetCount>,
+    cameras: Vec<FacetCount>,
+    lenses: Vec<FacetCount>,
+    places: Vec<PlaceCount>,
+}
+
+#[derive(FromRow)]
+struct FacetRow {
+    facet: String,
+    value: String,
+    region: Option<String>,
+    city: Option<String>,
+    count: i64,
+}
+
+/// Count the facets of the images in `matched`
+///
+/// `builder` must hold a `WITH RECURSIVE matched AS (…)` clause selecting the `id`, `taken_at`,
+/// `metadata`, `country`, `region` and `city` of the matching images.
+pub async fn load_facets(
+    mut builder: QueryBuilder<'_, Postgres>,
+    pool: &Pool<Postgres>,
+) -> Result<Facets, AppError> {
+    builder.push(
+        "
+            , subtree (ancestor_id, descendant_id) AS (
+                SELECT id, id FROM tag
+                UNION ALL
+                SELECT subtree.ancestor_id, child.id
+                FROM subtree JOIN tag child ON child.parent_id = subtree.descendant_id
+            ), dated AS (
+                SELECT id, taken_at AT TIME ZONE 'UTC' taken FROM matched WHERE taken_at IS NOT NULL
+            ), described AS (
+                SELECT
+                    BTRIM(COALESCE(metadata::jsonb ->> 'Model', metadata::jsonb ->> 'com.apple.quicktime.model'), '\" ') camera,
+                    BTRIM(metadata::jsonb ->> 'LensModel', '\" ') lens
+                FROM matched
+            )
+            (
+                SELECT 'tag' facet, tag.path value, NULL region, NULL city, COUNT(DISTINCT matched.id) count
+                FROM matched
+                JOIN image_tag ON image_tag.image_id = matched.id
+                JOIN subtree ON subtree.descendant_id = image_tag.tag_id
+                JOIN tag ON tag.id = subtree.ancestor_id
+                GROUP BY tag.path
+                ORDER BY count DESC, value
+                LIMIT ",
+    );
+    builder.push_bind(MAX_FACET_VALUES).push(
+        "
+            ) UNION ALL (
+                SELECT 'year', TO_CHAR(taken, 'YYYY'), NULL, NULL, COUNT(*) count
+                FROM dated
+                GROUP BY 2
+                ORDER BY 2 DESC
+            ) UNION ALL (
+                SELECT 'month', TO_CHAR(taken, 'YYYY-MM'), NULL, NULL, COUNT(*) count
+                FROM dated
+                GROUP BY 2
+                ORDER BY 2 DESC
+            ) UNION ALL (
+                SELECT 'camera', camera, NULL, NULL, COUNT(*) count
+                FROM described
+                WHERE camera <> ''
+                GROUP BY camera
+                ORDER BY count DESC, camera
+                LIMIT ",
+    );
+    builder.push_bind(MAX_FACET_VALUES).push(
+        "
+            ) UNION ALL (
+                SELECT 'lens', lens, NULL, NULL, COUNT(*) count
+                FROM described
+                WHERE lens <> ''
+                GROUP BY lens
+                ORDER BY count DESC, lens
+                LIMIT ",
+    );
+    builder.push_bind(MAX_FACET_VALUES).push(
+        "
+            ) UNION ALL (
+                SELECT 'place', country, region, city, COUNT(*) count
+                FROM matched
+                WHERE country IS NOT NULL
+                GROUP BY country, region, city
+                ORDER BY count DESC, country, region, city
+                LIMIT ",
+    );
+    builder.push_bind(MAX_FACET_VALUES).push(")");
+
+    let rows = builder.build_query_as::<FacetRow>().fetch_all(pool).await?;
+
+    let mut facets = Facets::default();
+    for row in rows {
+        let count = FacetCount {
+            value: row.value,
+            count: row.count,
+        };
+        match row.facet.as_str() {
+            "tag" => facets.tags.push(count),
+            "year" => facets.years.push(count),
+            "month" => facets.months.push(count),
+            "camera" => facets.cameras.push(count),
+            "lens" => facets.lenses.push(count),
+            "place" => facets.places.push(PlaceCount {
+                country: count.value,
+                region: row.region,
+                city: row.city,
+                count: count.count,
+            }),
+            _ => {}
+        }
+    }
+
+    Ok(facets)
+}
@@ -0,0 +1,236 @@
+use std::path::Path;
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView};
+use jiff::Timestamp;
+use sqlx::query;
+use tracing::{info, warn};
+
+use crate::{color::dominant_colors, decode::decode_image_blocking, error::AppError, AppState};
+
+/// Levels per RGB channel of the color histogram
+const HISTOGRAM_LEVELS: usize = 4;
+const HISTOGRAM_BINS: usize = HISTOGRAM_LEVELS * HISTOGRAM_LEVELS * HISTOGRAM_LEVELS;
+
+/// What an image looks like, in a form cheap enough to compare against the whole library
+pub struct Fingerprint {
+    pub dhash: i64,
+    pub histogram: Vec<f32>,
+}
+
+pub fn fingerprint(image: &DynamicImage) -> Fingerprint {
+    Fingerprint {
+        dhash: dhash(image),
+        histogram: color_histogram(image),
+    }
+}
+
+/// A fingerprint along with the dominant colors searched by `color:`
+pub struct ColorFingerprint {
+    pub fingerprint: Fingerprint,
+    pub palette: Vec<i32>,
+}
+
+pub fn color_fingerprint(image: &DynamicImage) -> ColorFingerprint {
+    ColorFingerprint {
+        fingerprint: fingerprint(image),
+        palette: dominant_colors(image),
+    }
+}
+
+/// Difference hash of an image
+///
+/// Each of the 64 bits tells whether a pixel of the 9x8 grayscale thumbnail is brighter than
+/// its right neighbour. Resized, recompressed and re-exported copies end up within a few bits
+/// of each other.
+pub fn dhash(image: &DynamicImage) -> i64 {
+    let thumbnail = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if thumbnail.get_pixel(x, y)[0] > thumbnail.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+
+    // Postgres has no unsigned integers, the bits are stored as they are
+    hash as i64
+}
+
+/// Share of pixels falling into each of the 64 coarse RGB colors
+pub fn color_histogram(image: &DynamicImage) -> Vec<f32> {
+    let thumbnail = image.thumbnail(64, 64).to_rgb8();
+
+    let mut histogram = vec![0f32; HISTOGRAM_BINS];
+    for pixel in thumbnail.pixels() {
+        let [r, g, b] = pixel.0.map(|c| c as usize * HISTOGRAM_LEVELS / 256);
+        histogram[(r * HISTOGRAM_LEVELS + g) * HISTOGRAM_LEVELS + b] += 1.0;
+    }
+
+    let pixels = (thumbnail.width() * thumbnail.height()).max(1) as f32;
+    histogram.iter_mut().for_each(|bin| *bin /= pixels);
+
+    histogram
+}
+
+/// BlurHash of the image, around 30 characters the UI paints as a blurred preview
+///
+/// More components along the longer side keep the blur roughly square.
+pub fn placeholder(image: &DynamicImage) -> Option<String> {
+    let thumbnail = image.thumbnail(32, 32).to_rgba8();
+    let (components_x, components_y) = if thumbnail.width() >= thumbnail.height() {
+        (4, 3)
+    } else {
+        (3, 4)
+    };
+
+    blurhash::encode(
+        components_x,
+        components_y,
+        thumbnail.width(),
+        thumbnail.height(),
+        thumbnail.as_raw(),
+    )
+    .ok()
+}
+
+pub fn hamming_distance(a: i64, b: i64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Visual distance between two images from 0 (identical) to 1
+///
+/// The hash captures structure and the histogram colors, so photos of the same scene
+/// shot from slightly different angles still end up close.
+pub fn visual_distance(a: &Fingerprint, b: &Fingerprint) -> f32 {
+    let structure = hamming_distance(a.dhash, b.dhash) as f32 / 64.0;
+
+    let intersection: f32 = a
+        .histogram
+        .iter()
+        .zip(&b.histogram)
+        .map(|(a, b)| a.min(*b))
+        .sum();
+    let color = 1.0 - intersection.min(1.0);
+
+    (structure + color) / 2.0
+}
+
+/// Compute the fingerprints of images indexed before they were introduced
+///
+/// Images that fail to decode are marked and left alone by later scans.
+#[tracing::instrument(skip_all)]
+pub async fn backfill_fingerprints(state: &AppState) -> Result<(), AppError> {
+    let images = query!(
+        "
+            SELECT id, filename
+            FROM image
+            WHERE (dhash IS NULL OR histogram IS NULL OR palette IS NULL)
+                AND media_kind = 'image' AND fingerprint_failed_at IS NULL;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    if images.is_empty() {
+        return Ok(());
+    }
+
+    let mut computed = 0;
+    for image in images {
+        let decoded = match decode_image_blocking(Path::new(&image.filename)).await {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                warn!(message = "decoding image for fingerprint failed", image_id = %image.id, %error);
+                query!(
+                    "UPDATE image SET fingerprint_failed_at = $2 WHERE id = $1;",
+                    image.id,
+                    Timestamp::now().to_string()
+                )
+                .execute(&state.pool)
+                .await?;
+                continue;
+            }
+        };
+        let ColorFingerprint {
+            fingerprint,
+            palette,
+        } = color_fingerprint(&decoded);
+
+        query!(
+            "UPDATE image SET dhash = $2, histogram = $3, palette = $4 WHERE id = $1;",
+            image.id,
+            fingerprint.dhash,
+            &fingerprint.histogram,
+            &palette
+        )
+        .execute(&state.pool)
+        .await?;
+        computed += 1;
+    }
+
+    info!(message = "computed fingerprints", images = computed);
+
+    Ok(())
+}
+
+/// Compute the placeholders of images and videos indexed before they were introduced
+///
+/// Videos indexed without a poster frame also get their aspect ratio here. Files that fail to
+/// decode or encode are marked and left alone by later scans.
+#[tracing::instrument(skip_all)]
+pub async fn backfill_placeholders(state: &AppState) -> Result<(), AppError> {
+    let images = query!(
+        "SELECT id, filename FROM image WHERE placeholder IS NULL AND placeholder_failed_at IS NULL;"
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    if images.is_empty() {
+        return Ok(());
+    }
+
+    let mut computed = 0;
+    for image in images {
+        // Videos decode to their poster frame
+        let placeholder = match decode_image_blocking(Path::new(&image.filename)).await {
+            Ok(decoded) => placeholder(&decoded).map(|placeholder| {
+                let (width, height) = decoded.dimensions();
+                (placeholder, width as f64 / height as f64)
+            }),
+            Err(error) => {
+                warn!(message = "decoding image for placeholder failed", image_id = %image.id, %error);
+                None
+            }
+        };
+
+        match placeholder {
+            Some((placeholder, aspect_ratio)) => {
+                query!(
+                    "UPDATE image SET placeholder = $2, aspect_ratio = COALESCE(aspect_ratio, $3) WHERE id = $1;",
+                    image.id,
+                    placeholder,
+                    aspect_ratio
+                )
+                .execute(&state.pool)
+                .await?;
+                computed += 1;
+            }
+            None => {
+                query!(
+                    "UPDATE image SET placeholder_failed_at = $2 WHERE id = $1;",
+                    image.id,
+                    Timestamp::now().to_string()
+                )
+                .execute(&state.pool)
+                .await?;
+            }
+        }
+    }
+
+    info!(message = "computed placeholders", images = computed);
+
+    Ok(())
+}
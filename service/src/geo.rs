@@ -0,0 +1,239 @@
+use std::{collections::HashMap, str::FromStr};
+
+use axum::http::StatusCode;
+use sqlx::query;
+use tracing::info;
+
+use crate::{error::AppError, AppState};
+
+/// Where an image was taken, from the EXIF GPS tags of photos or the ISO 6709 location
+/// tag phones write into videos
+pub fn coordinates(metadata: &HashMap<String, String>) -> Option<(f64, f64)> {
+    let exif = || {
+        let latitude = parse_dms(metadata.get("GPSLatitude")?)?;
+        let longitude = parse_dms(metadata.get("GPSLongitude")?)?;
+        let sign = |reference: &str, negative: &str| match metadata.get(reference) {
+            Some(value) if value.trim().eq_ignore_ascii_case(negative) => -1.0,
+            _ => 1.0,
+        };
+        Some((
+            latitude * sign("GPSLatitudeRef", "S"),
+            longitude * sign("GPSLongitudeRef", "W"),
+        ))
+    };
+    let video = || {
+        metadata
+            .get("com.apple.quicktime.location.ISO6709")
+            .or(metadata.get("location"))
+            .and_then(|location| parse_iso6709(location))
+    };
+
+    // Cameras without a fix sometimes write 0/0
+    exif().or_else(video).filter(|(latitude, longitude)| {
+        (*latitude != 0.0 || *longitude != 0.0)
+            && (-90.0..=90.0).contains(latitude)
+            && (-180.0..=180.0).contains(longitude)
+    })
+}
+
+/// Parse the EXIF display value `47 deg 22 min 1.5 sec` into decimal degrees
+fn parse_dms(value: &str) -> Option<f64> {
+    let parts = value.split_whitespace().collect::<Vec<_>>();
+    let [degrees, "deg", minutes, "min", seconds, "sec"] = parts.as_slice() else {
+        return None;
+    };
+
+    Some(
+        degrees.parse::<f64>().ok()?
+            + minutes.parse::<f64>().ok()? / 60.0
+            + seconds.parse::<f64>().ok()? / 3600.0,
+    )
+}
+
+/// Parse `+47.3769+008.5417/` or `+47.3769+008.5417+408.000/`
+fn parse_iso6709(value: &str) -> Option<(f64, f64)> {
+    let value = value.trim().trim_end_matches('/');
+    let signs = value
+        .char_indices()
+        .filter(|(_, c)| *c == '+' || *c == '-')
+        .map(|(i, _)| i)
+        .chain([value.len()])
+        .collect::<Vec<_>>();
+
+    let latitude = value.get(*signs.first()?..*signs.get(1)?)?.parse().ok()?;
+    let longitude = value.get(*signs.get(1)?..*signs.get(2)?)?.parse().ok()?;
+
+    Some((latitude, longitude))
+}
+
+/// Area given as `west,south,east,north` in degrees
+///
+/// `west` is larger than `east` for boxes crossing the antimeridian, `south` can't be larger
+/// than `north`.
+#[derive(Debug, Clone, Copy)]
+pub struct BoundingBox {
+    pub west: f64,
+    pub south: f64,
+    pub east: f64,
+    pub north: f64,
+}
+
+impl From<[f64; 4]> for BoundingBox {
+    fn from([west, south, east, north]: [f64; 4]) -> Self {
+        BoundingBox {
+            west,
+            south,
+            east,
+            north,
+        }
+    }
+}
+
+impl FromStr for BoundingBox {
+    type Err = AppError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            AppError::Text(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "invalid bounding box '{}', expected 'west,south,east,north'",
+                    value
+                ),
+            )
+        };
+
+        let values = value
+            .split(',')
+            .map(|v| v.trim().parse::<f64>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let values: [f64; 4] = values.try_into().map_err(|_| invalid())?;
+        let bbox = BoundingBox::from(values);
+
+        let longitudes = -180.0..=180.0;
+        let latitudes = -90.0..=90.0;
+        if !longitudes.contains(&bbox.west)
+            || !longitudes.contains(&bbox.east)
+            || !latitudes.contains(&bbox.south)
+            || !latitudes.contains(&bbox.north)
+            || bbox.south > bbox.north
+        {
+            return Err(invalid());
+        }
+
+        Ok(bbox)
+    }
+}
+
+/// Read the coordinates of images indexed before they were stored separately
+///
+/// Uses the metadata saved at ingest, so no file has to be read again.
+#[tracing::instrument(skip_all)]
+pub async fn backfill_locations(state: &AppState) -> Result<(), AppError> {
+    let images = query!(
+        "
+            SELECT id, metadata
+            FROM image
+            WHERE latitude IS NULL
+                AND (metadata LIKE '%\"GPSLatitude\"%' OR metadata LIKE '%ISO6709%' OR metadata LIKE '%\"location\"%');
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut located = 0;
+    for image in images {
+        let metadata: HashMap<String, String> =
+            serde_json::from_str(&image.metadata.unwrap_or_default()).unwrap_or_default();
+        let Some((latitude, longitude)) = coordinates(&metadata) else {
+            continue;
+        };
+
+        query!(
+            "UPDATE image SET latitude = $2, longitude = $3 WHERE id = $1;",
+            image.id,
+            latitude,
+            longitude
+        )
+        .execute(&state.pool)
+        .await?;
+        located += 1;
+    }
+
+    if located > 0 {
+        info!(message = "stored image locations", images = located);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exif_degrees_minutes_seconds() {
+        assert_eq!(parse_dms("47 deg 22 min 36 sec"), Some(47.376666666666665));
+        assert_eq!(parse_dms("8 deg 30 min 0 sec"), Some(8.5));
+        assert_eq!(parse_dms("47 deg 22 min"), None);
+        assert_eq!(parse_dms("47° 22' 36\""), None);
+    }
+
+    #[test]
+    fn exif_references_set_the_sign() {
+        let metadata = HashMap::from([
+            ("GPSLatitude".to_string(), "22 deg 54 min 0 sec".to_string()),
+            ("GPSLatitudeRef".to_string(), "S".to_string()),
+            (
+                "GPSLongitude".to_string(),
+                "43 deg 12 min 0 sec".to_string(),
+            ),
+            ("GPSLongitudeRef".to_string(), "W".to_string()),
+        ]);
+
+        assert_eq!(coordinates(&metadata), Some((-22.9, -43.2)));
+    }
+
+    #[test]
+    fn iso6709_locations() {
+        assert_eq!(parse_iso6709("+47.3769+008.5417/"), Some((47.3769, 8.5417)));
+        assert_eq!(
+            parse_iso6709("-22.9068-043.1729+011.000/"),
+            Some((-22.9068, -43.1729))
+        );
+        assert_eq!(parse_iso6709("+47.3769/"), None);
+        assert_eq!(parse_iso6709(""), None);
+    }
+
+    #[test]
+    fn missing_fix_is_no_location() {
+        let metadata = HashMap::from([("location".to_string(), "+00.0000+000.0000/".to_string())]);
+
+        assert_eq!(coordinates(&metadata), None);
+    }
+
+    #[test]
+    fn bounding_box_across_the_antimeridian() {
+        let bbox: BoundingBox = "170,-20,-170,10".parse().unwrap();
+
+        assert_eq!(
+            (bbox.west, bbox.south, bbox.east, bbox.north),
+            (170.0, -20.0, -170.0, 10.0)
+        );
+    }
+
+    #[test]
+    fn invalid_bounding_boxes() {
+        for value in [
+            "",
+            "1,2,3",
+            "1,2,3,4,5",
+            "a,2,3,4",
+            "10,20,30,10",
+            "-190,0,10,10",
+            "0,-95,10,10",
+        ] {
+            assert!(value.parse::<BoundingBox>().is_err(), "{}", value);
+        }
+    }
+}
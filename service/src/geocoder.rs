@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use sqlx::{query, Postgres, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    tag::{add_tags, delete_unused_tags, TagChangeRequest},
+    AppState,
+};
+
+const CITIES_FILE: &str = "cities15000.txt";
+const ADMIN1_FILE: &str = "admin1CodesASCII.txt";
+const COUNTRIES_FILE: &str = "countryInfo.txt";
+
+/// Cities further away than this don't describe where a photo was taken
+const MAX_DISTANCE_KM: f64 = 50.0;
+/// Size of the grid cells cities are indexed by, in degrees
+const CELL_SIZE: f64 = 1.0;
+
+/// Country, region and city of a location
+#[derive(Debug, Clone)]
+pub struct Place {
+    pub country: String,
+    pub region: Option<String>,
+    pub city: String,
+}
+
+impl Place {
+    /// A single tag path, like `france/auvergne-rhône-alpes/lyon`
+    pub fn tags(&self) -> Vec<String> {
+        vec![
+            [Some(&self.country), self.region.as_ref(), Some(&self.city)]
+                .into_iter()
+                .flatten()
+                .map(|name| name.replace('/', "-").to_lowercase())
+                .collect::<Vec<_>>()
+                .join("/"),
+        ]
+    }
+}
+
+struct City {
+    name: String,
+    latitude: f64,
+    longitude: f64,
+    country_code: String,
+    admin1_code: String,
+}
+
+/// Resolves coordinates to places with the GeoNames dumps in `GEONAMES_DIR`
+///
+/// Needs `cities15000.txt`. With `admin1CodesASCII.txt` and `countryInfo.txt` next to it,
+/// regions are resolved and countries get their names instead of ISO codes.
+pub struct Geocoder {
+    cities: Vec<City>,
+    grid: HashMap<(i32, i32), Vec<usize>>,
+    regions: HashMap<String, String>,
+    countries: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for Geocoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Geocoder")
+            .field("cities", &self.cities.len())
+            .finish()
+    }
+}
+
+impl Geocoder {
+    /// Load the dataset if `GEONAMES_DIR` is set
+    pub fn load() -> Result<Option<Self>, AppError> {
+        let Ok(directory) = std::env::var("GEONAMES_DIR") else {
+            return Ok(None);
+        };
+        let directory = PathBuf::from(directory);
+
+        let mut cities = vec![];
+        for line in fs::read_to_string(directory.join(CITIES_FILE))?.lines() {
+            let columns = line.split('\t').collect::<Vec<_>>();
+            if columns.len() < 11 {
+                continue;
+            }
+            let (Ok(latitude), Ok(longitude)) = (columns[4].parse(), columns[5].parse()) else {
+                continue;
+            };
+            cities.push(City {
+                name: columns[1].to_string(),
+                latitude,
+                longitude,
+                country_code: columns[8].to_string(),
+                admin1_code: columns[10].to_string(),
+            });
+        }
+
+        let mut grid: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for (index, city) in cities.iter().enumerate() {
+            grid.entry(cell(city.latitude, city.longitude))
+                .or_default()
+                .push(index);
+        }
+
+        // `CH.25	Zurich	Zurich	2657895`
+        let regions = read_optional(&directory.join(ADMIN1_FILE))?
+            .lines()
+            .filter_map(|line| {
+                let mut columns = line.split('\t');
+                Some((columns.next()?.to_string(), columns.next()?.to_string()))
+            })
+            .collect();
+
+        // `CH	CHE	756	SZ	Switzerland	Bern	…`, with comments starting with `#`
+        let countries = read_optional(&directory.join(COUNTRIES_FILE))?
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let columns = line.split('\t').collect::<Vec<_>>();
+                Some((columns.first()?.to_string(), columns.get(4)?.to_string()))
+            })
+            .collect();
+
+        info!(message = "Loaded GeoNames cities", cities = cities.len());
+
+        Ok(Some(Geocoder {
+            cities,
+            grid,
+            regions,
+            countries,
+        }))
+    }
+
+    /// The nearest city within `MAX_DISTANCE_KM`
+    pub fn lookup(&self, latitude: f64, longitude: f64) -> Option<Place> {
+        let (row, column) = cell(latitude, longitude);
+
+        // A degree of longitude gets shorter towards the poles, so more cells are in reach
+        let kilometers_per_cell = 111.32 * CELL_SIZE * latitude.to_radians().cos().max(0.01);
+        let span = ((MAX_DISTANCE_KM / kilometers_per_cell).ceil() as i32).min(180);
+
+        let nearest = (-1..=1)
+            .flat_map(|dy| (-span..=span).map(move |dx| (row + dy, wrap_column(column + dx))))
+            .filter_map(|cell| self.grid.get(&cell))
+            .flatten()
+            .map(|&index| {
+                let city = &self.cities[index];
+                (
+                    haversine_km(latitude, longitude, city.latitude, city.longitude),
+                    city,
+                )
+            })
+            .filter(|(distance, _)| *distance <= MAX_DISTANCE_KM)
+            .min_by(|a, b| a.0.total_cmp(&b.0))?
+            .1;
+
+        Some(Place {
+            country: self
+                .countries
+                .get(&nearest.country_code)
+                .cloned()
+                .unwrap_or_else(|| nearest.country_code.clone()),
+            region: self
+                .regions
+                .get(&format!("{}.{}", nearest.country_code, nearest.admin1_code))
+                .cloned(),
+            city: nearest.name.clone(),
+        })
+    }
+}
+
+fn read_optional(file: &Path) -> Result<String, AppError> {
+    if !file.is_file() {
+        return Ok(String::new());
+    }
+    Ok(fs::read_to_string(file)?)
+}
+
+fn cell(latitude: f64, longitude: f64) -> (i32, i32) {
+    (
+        (latitude / CELL_SIZE).floor() as i32,
+        wrap_column((longitude / CELL_SIZE).floor() as i32),
+    )
+}
+
+fn wrap_column(column: i32) -> i32 {
+    let columns = (360.0 / CELL_SIZE) as i32;
+    (column + columns / 2).rem_euclid(columns) - columns / 2
+}
+
+fn haversine_km(latitude_a: f64, longitude_a: f64, latitude_b: f64, longitude_b: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+
+    let (phi_a, phi_b) = (latitude_a.to_radians(), latitude_b.to_radians());
+    let delta_phi = (latitude_b - latitude_a).to_radians();
+    let delta_lambda = (longitude_b - longitude_a).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2)
+        + phi_a.cos() * phi_b.cos() * (delta_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Store the place of the image and add its names as tags
+pub async fn apply_place<'c>(
+    image_id: Uuid,
+    place: Place,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    query!(
+        "UPDATE image SET country = $2, region = $3, city = $4 WHERE id = $1;",
+        image_id,
+        place.country,
+        place.region,
+        place.city
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    add_tags(
+        TagChangeRequest {
+            image_ids: vec![image_id],
+            tags: place.tags(),
+        },
+        tx,
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Forget the place of the image and remove the tags it added, as its location changed
+pub async fn clear_place<'c>(
+    image_id: Uuid,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    let previous = query!(
+        "SELECT country, region, city FROM image WHERE id = $1;",
+        image_id
+    )
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    query!(
+        "UPDATE image SET country = NULL, region = NULL, city = NULL WHERE id = $1;",
+        image_id
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let Some((country, city, region)) =
+        previous.and_then(|p| Some((p.country?, p.city?, p.region)))
+    else {
+        return Ok(());
+    };
+    let paths = Place {
+        country,
+        region,
+        city,
+    }
+    .tags();
+
+    query!(
+        "DELETE FROM image_tag USING tag WHERE image_tag.tag_id = tag.id AND image_tag.image_id = $1 AND tag.path = ANY($2);",
+        image_id,
+        &paths
+    )
+    .execute(&mut **tx)
+    .await?;
+    delete_unused_tags(&paths, tx).await?;
+
+    Ok(())
+}
+
+/// Resolve the places of located images that don't have one yet
+#[tracing::instrument(skip_all)]
+pub async fn backfill_places(state: &AppState) -> Result<(), AppError> {
+    let Some(geocoder) = &state.geocoder else {
+        return Ok(());
+    };
+
+    let images = query!(
+        "
+            SELECT id, latitude, longitude
+            FROM image
+            WHERE latitude IS NOT NULL AND longitude IS NOT NULL AND country IS NULL;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut resolved = 0;
+    for image in images {
+        let (Some(latitude), Some(longitude)) = (image.latitude, image.longitude) else {
+            continue;
+        };
+        // Nothing nearby, e.g. taken at sea
+        let Some(place) = geocoder.lookup(latitude, longitude) else {
+            continue;
+        };
+
+        let mut tx = state.pool.begin().await?;
+        apply_place(image.id, place, &mut tx).await?;
+        tx.commit().await?;
+        resolved += 1;
+    }
+
+    if resolved > 0 {
+        info!(message = "resolved places", images = resolved);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,426 @@
+use std::path;
+
+use axum::{
+    extract::{Multipart, State},
+    http::StatusCode,
+    Json,
+};
+use jiff::{SignedDuration, Timestamp};
+use quick_xml::{events::Event, Reader};
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthenticatedAccount,
+    error::AppError,
+    geocoder::{apply_place, clear_place},
+    AppState,
+};
+
+/// Positions further apart in time than this aren't interpolated, e.g. across a lunch break
+/// with the logger switched off
+const DEFAULT_MAX_GAP_SECONDS: i64 = 300;
+/// Cameras are off by time zones, not weeks
+const MAX_OFFSET_SECONDS: i64 = 14 * 24 * 60 * 60;
+const MAX_GAP_SECONDS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy)]
+struct TrackPoint {
+    time: Timestamp,
+    latitude: f64,
+    longitude: f64,
+}
+
+#[derive(Serialize)]
+pub struct GeotagMatch {
+    image_id: Uuid,
+    file_name: String,
+    captured_at: String,
+    latitude: f64,
+    longitude: f64,
+    /// Seconds to the nearest track point
+    distance_seconds: i64,
+}
+
+#[derive(Serialize)]
+pub struct GeotagResponse {
+    matches: Vec<GeotagMatch>,
+    /// Images in the track's time range that already have a location and were left alone
+    already_located: usize,
+    applied: bool,
+}
+
+/// Match images to the positions of a GPX track by capture time
+///
+/// Takes a multipart form with
+/// - `gpx`: the track
+/// - `offset`: seconds added to `captured_at` to get UTC, e.g. `-7200` for a camera set to
+///   CEST, as capture times without a time zone are stored as UTC, at most 14 days
+/// - `max_gap`: seconds between track points still interpolated, 300 by default and at most
+///   a day
+/// - `overwrite`: also match images that already have a location
+/// - `apply`: write the coordinates, otherwise only the matches are returned for review
+/// - `write_sidecars`: also write the coordinates to the XMP sidecars
+///
+/// Originals are never touched.
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+))]
+pub async fn geotag_from_gpx(
+    account: AuthenticatedAccount,
+    State(state): State<AppState>,
+    mut multipart: Multipart,
+) -> Result<Json<GeotagResponse>, AppError> {
+    let mut gpx = None;
+    let mut offset = 0;
+    let mut max_gap = DEFAULT_MAX_GAP_SECONDS;
+    let mut overwrite = false;
+    let mut apply = false;
+    let mut write_sidecars = false;
+
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        let value = field.text().await?;
+        match name.as_str() {
+            "gpx" => gpx = Some(value),
+            "offset" => offset = parse_field(&name, &value)?,
+            "max_gap" => max_gap = parse_field(&name, &value)?,
+            "overwrite" => overwrite = parse_field(&name, &value)?,
+            "apply" => apply = parse_field(&name, &value)?,
+            "write_sidecars" => write_sidecars = parse_field(&name, &value)?,
+            _ => {}
+        }
+    }
+
+    if !(-MAX_OFFSET_SECONDS..=MAX_OFFSET_SECONDS).contains(&offset) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("offset must be between -{0} and {0}", MAX_OFFSET_SECONDS),
+        ));
+    }
+    if !(1..=MAX_GAP_SECONDS).contains(&max_gap) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("max_gap must be between 1 and {}", MAX_GAP_SECONDS),
+        ));
+    }
+
+    let Some(gpx) = gpx else {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "missing field 'gpx'".to_string(),
+        ));
+    };
+    if write_sidecars && state.sidecars.is_none() {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            "XMP write-back is not enabled".to_string(),
+        ));
+    }
+
+    let track = parse_gpx(&gpx)?;
+    let (Some(first), Some(last)) = (track.first(), track.last()) else {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "the GPX file has no track points with a time".to_string(),
+        ));
+    };
+
+    info!(
+        message = "geotagging from GPX",
+        points = track.len(),
+        offset,
+        max_gap,
+        apply
+    );
+
+    let offset = SignedDuration::from_secs(offset);
+    let max_gap = SignedDuration::from_secs(max_gap);
+
+    #[derive(FromRow)]
+    struct Candidate {
+        id: Uuid,
+        filename: String,
+        captured_at: String,
+        latitude: Option<f64>,
+    }
+
+    // The range is widened by the offset in both directions, the exact check follows below
+    let slack = max_gap + offset.abs();
+    let out_of_range = |_| {
+        AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "the GPX track is out of the supported time range".to_string(),
+        )
+    };
+    let candidates = query_as!(
+        Candidate,
+        "
+            SELECT id, filename, captured_at, latitude
+            FROM image
+            WHERE taken_at BETWEEN $1::text::timestamptz AND $2::text::timestamptz
+            ORDER BY captured_at;
+        ",
+        first
+            .time
+            .checked_sub(slack)
+            .map_err(out_of_range)?
+            .to_string(),
+        last.time
+            .checked_add(slack)
+            .map_err(out_of_range)?
+            .to_string()
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut matches = vec![];
+    let mut already_located = 0;
+    for candidate in candidates {
+        let Ok(captured_at) = candidate.captured_at.parse::<Timestamp>() else {
+            continue;
+        };
+        let Ok(time) = captured_at.checked_add(offset) else {
+            continue;
+        };
+        let Some((point, distance)) = position_at(&track, time, max_gap) else {
+            continue;
+        };
+        if candidate.latitude.is_some() && !overwrite {
+            already_located += 1;
+            continue;
+        }
+
+        matches.push(GeotagMatch {
+            image_id: candidate.id,
+            file_name: path::Path::new(&candidate.filename)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            captured_at: candidate.captured_at,
+            latitude: point.latitude,
+            longitude: point.longitude,
+            distance_seconds: distance.as_secs(),
+        });
+    }
+
+    if apply {
+        let mut tx = state.pool.begin().await?;
+        for matched in &matches {
+            clear_place(matched.image_id, &mut tx).await?;
+            query!(
+                "UPDATE image SET latitude = $2, longitude = $3 WHERE id = $1;",
+                matched.image_id,
+                matched.latitude,
+                matched.longitude
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            if let Some(place) = state
+                .geocoder
+                .as_ref()
+                .and_then(|geocoder| geocoder.lookup(matched.latitude, matched.longitude))
+            {
+                apply_place(matched.image_id, place, &mut tx).await?;
+            }
+        }
+        tx.commit().await?;
+
+        if write_sidecars {
+            if let Some(sidecars) = &state.sidecars {
+                sidecars.queue(&matches.iter().map(|m| m.image_id).collect::<Vec<_>>());
+            }
+        }
+
+        info!(message = "geotagged images", images = matches.len());
+    }
+
+    Ok(Json(GeotagResponse {
+        matches,
+        already_located,
+        applied: apply,
+    }))
+}
+
+fn parse_field<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, AppError> {
+    value.trim().parse().map_err(|_| {
+        AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("invalid value '{}' for field '{}'", value, name),
+        )
+    })
+}
+
+/// Read the timed track points of all tracks and segments, ordered by time
+fn parse_gpx(gpx: &str) -> Result<Vec<TrackPoint>, AppError> {
+    let mut reader = Reader::from_str(gpx);
+    reader.config_mut().trim_text(true);
+
+    let mut points = vec![];
+    // Position of the `trkpt` being read, waiting for its `time`
+    let mut current: Option<(f64, f64)> = None;
+    let mut in_time = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) if element.local_name().as_ref() == b"trkpt" => {
+                let mut latitude = None;
+                let mut longitude = None;
+                for attribute in element.attributes().flatten() {
+                    let value = attribute.unescape_value()?;
+                    match attribute.key.as_ref() {
+                        b"lat" => latitude = value.trim().parse().ok(),
+                        b"lon" => longitude = value.trim().parse().ok(),
+                        _ => {}
+                    }
+                }
+                current = latitude.zip(longitude);
+            }
+            Event::End(element) if element.local_name().as_ref() == b"trkpt" => current = None,
+            Event::Start(element) if element.local_name().as_ref() == b"time" => in_time = true,
+            Event::End(element) if element.local_name().as_ref() == b"time" => in_time = false,
+            Event::Text(text) if in_time => {
+                if let (Some((latitude, longitude)), Ok(time)) =
+                    (current, text.unescape()?.trim().parse::<Timestamp>())
+                {
+                    points.push(TrackPoint {
+                        time,
+                        latitude,
+                        longitude,
+                    });
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    points.sort_by_key(|point| point.time);
+
+    Ok(points)
+}
+
+/// Position at `time`, interpolated between the surrounding track points
+///
+/// Returns the position and the time to the nearest track point, or `None` if the track has
+/// no points within `max_gap`.
+fn position_at(
+    track: &[TrackPoint],
+    time: Timestamp,
+    max_gap: SignedDuration,
+) -> Option<(TrackPoint, SignedDuration)> {
+    let after = track.partition_point(|point| point.time < time);
+
+    let previous = after.checked_sub(1).map(|index| track[index]);
+    let next = track.get(after).copied();
+
+    match (previous, next) {
+        (Some(previous), Some(next)) if next.time.duration_since(previous.time) <= max_gap => {
+            let span = next.time.duration_since(previous.time).as_secs_f64();
+            let fraction = if span > 0.0 {
+                time.duration_since(previous.time).as_secs_f64() / span
+            } else {
+                0.0
+            };
+            let distance = time
+                .duration_since(previous.time)
+                .min(next.time.duration_since(time));
+
+            Some((
+                TrackPoint {
+                    time,
+                    latitude: previous.latitude + (next.latitude - previous.latitude) * fraction,
+                    longitude: previous.longitude
+                        + (next.longitude - previous.longitude) * fraction,
+                },
+                distance,
+            ))
+        }
+        // Outside the track or in a gap, the closest point still counts if it's near enough
+        (previous, next) => [previous, next]
+            .into_iter()
+            .flatten()
+            .map(|point| (point, time.duration_since(point.time).abs()))
+            .filter(|(_, distance)| *distance <= max_gap)
+            .min_by_key(|(_, distance)| *distance),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPX: &str = r#"<?xml version="1.0"?>
+<gpx version="1.1" xmlns="http://www.topografix.com/GPX/1/1">
+  <trk><trkseg>
+    <trkpt lat="47.0" lon="8.0"><time>2024-06-01T10:00:00Z</time></trkpt>
+    <trkpt lat="47.1" lon="8.2"><time>2024-06-01T10:01:40Z</time></trkpt>
+    <trkpt lat="46.0" lon="7.0"></trkpt>
+  </trkseg></trk>
+  <trk><trkseg>
+    <trkpt lat="48.0" lon="9.0"><time>2024-06-01T12:00:00Z</time></trkpt>
+  </trkseg></trk>
+</gpx>"#;
+
+    fn at(time: &str) -> Timestamp {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn gpx_points_with_a_time() {
+        let track = parse_gpx(GPX).unwrap();
+
+        assert_eq!(
+            track
+                .iter()
+                .map(|point| (point.latitude, point.longitude))
+                .collect::<Vec<_>>(),
+            [(47.0, 8.0), (47.1, 8.2), (48.0, 9.0)]
+        );
+        assert_eq!(track[2].time, at("2024-06-01T12:00:00Z"));
+    }
+
+    #[test]
+    fn interpolates_between_points() {
+        let track = parse_gpx(GPX).unwrap();
+        let (point, distance) = position_at(
+            &track,
+            at("2024-06-01T10:00:25Z"),
+            SignedDuration::from_secs(300),
+        )
+        .unwrap();
+
+        assert!((point.latitude - 47.025).abs() < 1e-9);
+        assert!((point.longitude - 8.05).abs() < 1e-9);
+        assert_eq!(distance, SignedDuration::from_secs(25));
+    }
+
+    #[test]
+    fn gaps_use_the_nearest_point_within_max_gap() {
+        let track = parse_gpx(GPX).unwrap();
+        let max_gap = SignedDuration::from_secs(300);
+
+        let (point, distance) = position_at(&track, at("2024-06-01T11:58:00Z"), max_gap).unwrap();
+        assert_eq!((point.latitude, point.longitude), (48.0, 9.0));
+        assert_eq!(distance, SignedDuration::from_secs(120));
+
+        assert!(position_at(&track, at("2024-06-01T11:00:00Z"), max_gap).is_none());
+        assert!(position_at(&track, at("2024-06-01T09:50:00Z"), max_gap).is_none());
+    }
+
+    #[test]
+    fn offset_moves_local_capture_times_onto_the_track() {
+        let track = parse_gpx(GPX).unwrap();
+        // Captured at 12:01:40 on a camera set to CEST
+        let time = at("2024-06-01T12:01:40Z")
+            .checked_add(SignedDuration::from_secs(-7200))
+            .unwrap();
+
+        let (point, distance) = position_at(&track, time, SignedDuration::from_secs(300)).unwrap();
+        assert_eq!((point.latitude, point.longitude), (47.1, 8.2));
+        assert_eq!(distance, SignedDuration::ZERO);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use tracing::warn;
+
+/// Name of the gitignore-style file honored in every folder of the library
+pub const IGNORE_FILE_NAME: &str = ".reflectiveignore";
+
+/// Files that are never photos, left behind by operating systems, NAS indexers and editors,
+/// and sidecars which are read along with their image
+const DEFAULT_IGNORES: &[&str] = &[
+    ".DS_Store",
+    "._*",
+    "Thumbs.db",
+    "desktop.ini",
+    "@eaDir/",
+    ".@__thumb/",
+    "*.lrcat",
+    "*.lrcat-*",
+    "*.lrdata/",
+    "*.xmp",
+    "*.XMP",
+    "*.json",
+    "trigger-scan",
+    IGNORE_FILE_NAME,
+];
+
+/// Decides which paths below a library root the scanner looks at
+///
+/// Besides the built-in defaults, `SCAN_IGNORE` adds comma-separated global globs and
+/// `SCAN_EXTENSIONS` restricts files to a comma-separated list of extensions.
+pub struct IgnoreRules {
+    root: PathBuf,
+    global: Gitignore,
+    extensions: Option<Vec<String>>,
+    folders: HashMap<PathBuf, Gitignore>,
+}
+
+impl IgnoreRules {
+    pub fn new(root: &Path) -> Self {
+        let mut builder = GitignoreBuilder::new(root);
+        let configured = std::env::var("SCAN_IGNORE").unwrap_or_default();
+        let patterns = DEFAULT_IGNORES
+            .iter()
+            .copied()
+            .chain(configured.split(',').map(str::trim))
+            .filter(|pattern| !pattern.is_empty());
+
+        for pattern in patterns {
+            if let Err(error) = builder.add_line(None, pattern) {
+                warn!(message = "invalid ignore pattern", %pattern, %error);
+            }
+        }
+
+        let global = builder.build().unwrap_or_else(|error| {
+            warn!(message = "building ignore rules failed", %error);
+            Gitignore::empty()
+        });
+
+        let extensions = std::env::var("SCAN_EXTENSIONS").ok().map(|extensions| {
+            extensions
+                .split(',')
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        });
+
+        IgnoreRules {
+            root: root.to_path_buf(),
+            global,
+            extensions,
+            folders: HashMap::new(),
+        }
+    }
+
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        if path == self.root {
+            return false;
+        }
+
+        if !is_dir {
+            if let Some(extensions) = &self.extensions {
+                let extension = path
+                    .extension()
+                    .and_then(|e| e.to_str())
+                    .map(|e| e.to_lowercase())
+                    .unwrap_or_default();
+                if !extensions.contains(&extension) {
+                    return true;
+                }
+            }
+        }
+
+        // Like git, rules in deeper folders take precedence over the ones above them
+        let folders = path
+            .ancestors()
+            .skip(1)
+            .take_while(|folder| folder.starts_with(&self.root))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
+        for folder in folders {
+            match self.folder_rules(&folder).matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+
+        self.global.matched(path, is_dir).is_ignore()
+    }
+
+    fn folder_rules(&mut self, folder: &Path) -> &Gitignore {
+        self.folders.entry(folder.to_path_buf()).or_insert_with(|| {
+            let file = folder.join(IGNORE_FILE_NAME);
+            if !file.is_file() {
+                return Gitignore::empty();
+            }
+
+            let (rules, error) = Gitignore::new(&file);
+            if let Some(error) = error {
+                warn!(message = "reading ignore file failed", file = ?file, %error);
+            }
+            rules
+        })
+    }
+}
@@ -1,83 +1,205 @@
 use std::{
     collections::HashMap,
     fs::{self},
-    path,
+    path::PathBuf,
     time::Duration,
     vec,
 };
 
 use crate::{
     auth::AuthenticatedAccount,
+    cursor::{decode_cursor, encode_cursor},
+    decode::{decode_image_blocking, read_exif, source_format, SourceFormat},
     error::AppError,
-    tag::{add_tags, TagChangeRequest},
+    facets::{load_facets, Facets},
+    fingerprint::{
+        backfill_fingerprints, backfill_placeholders, color_fingerprint, placeholder,
+        visual_distance, Fingerprint,
+    },
+    geo::{backfill_locations, coordinates, BoundingBox},
+    geocoder::backfill_places,
+    ignore_rules::IgnoreRules,
+    library::LibraryRoot,
+    photo_export::{apply_export_metadata, read_export_metadata, ExportMetadata, ExportSidecars},
+    query::{parse_period, parse_query, Expr},
+    sort::{Sort, SortOrder},
+    stack::stack_images,
+    tag::{add_tags, nest_folder_tags, TagChangeRequest},
     utils::{compress_image, get_object_name},
+    video::{probe, VideoInfo},
+    xmp::{apply_curated_metadata, read_curated_metadata, sync_sidecar},
 };
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
-    http::{header, StatusCode},
+    extract::{Path, Query, Request, State},
+    http::StatusCode,
     response::Response,
     Json,
 };
-use image::{GenericImageView, ImageDecoder, ImageReader};
-use jiff::{fmt::strtime, tz, Timestamp};
+use image::GenericImageView;
+use jiff::{fmt::strtime, tz, tz::TimeZone, Timestamp};
+use mime_guess::mime;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{query, query_as, FromRow};
-use tokio::{fs::File, time::interval};
-use tokio_util::io::ReaderStream;
+use sqlx::{query, query_as, FromRow, Postgres, QueryBuilder};
+use tokio::time::interval;
+use tower_http::services::ServeFile;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 use walkdir::{DirEntry, WalkDir};
 
 use crate::AppState;
 
+/// Scan the libraries whenever a `trigger-scan` file shows up in a writable root
+///
+/// The trigger is removed when the scan starts, so read-only roots can't hold one. Setups with
+/// only read-only roots point `SCAN_TRIGGER` at a file in a writable location instead.
 #[tracing::instrument(skip_all)]
 pub async fn scan_disk(state: AppState) -> Result<(), AppError> {
     let mut interval = interval(Duration::from_secs(60));
     interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
 
+    let mut locations = state
+        .libraries
+        .iter()
+        .filter(|library| !library.read_only)
+        .map(|library| library.path.join("./trigger-scan"))
+        .collect::<Vec<_>>();
+    locations.extend(std::env::var("SCAN_TRIGGER").ok().map(PathBuf::from));
+    if locations.is_empty() {
+        warn!(message = "all library roots are read-only and SCAN_TRIGGER is not set, scans can't be triggered");
+    }
+
     loop {
         interval.tick().await;
-        let images_dir = std::env::var("IMAGE_DIR").expect("IMAGE_DIR must be set");
-        let path = path::Path::new(&images_dir).join("./trigger-scan");
 
-        if path.exists() {
-            fs::remove_file(path)?;
+        let triggers = locations
+            .iter()
+            .filter(|path| path.exists())
+            .collect::<Vec<_>>();
+
+        if !triggers.is_empty() {
+            for path in triggers {
+                fs::remove_file(path)?;
+            }
             info!(message = "Starting scan for new images");
             verify_images(&state).await?;
         }
     }
 }
 
+/// Outcome of a library scan
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    files: usize,
+    added: usize,
+    skipped: usize,
+    failed: usize,
+}
+
 #[tracing::instrument(skip_all)]
-pub async fn verify_images(state: &AppState) -> Result<(), AppError> {
-    let images_dir = std::env::var("IMAGE_DIR").expect("IMAGE_DIR must be set");
+pub async fn verify_images(state: &AppState) -> Result<ScanReport, AppError> {
+    let mut report = ScanReport::default();
+
+    for library in state.libraries.iter() {
+        if let Err(error) = verify_library(state, library, &mut report).await {
+            error!(message = "scanning library failed", library = %library.name, %error);
+        }
+    }
+
+    if let Err(error) = stack_images(state).await {
+        error!(message = "stacking images failed", %error);
+    }
+
+    if let Err(error) = backfill_fingerprints(state).await {
+        error!(message = "computing fingerprints failed", %error);
+    }
+
+    if let Err(error) = backfill_placeholders(state).await {
+        error!(message = "computing placeholders failed", %error);
+    }
+
+    if let Err(error) = backfill_locations(state).await {
+        error!(message = "storing locations failed", %error);
+    }
+
+    if let Err(error) = nest_folder_tags(&state.pool, &state.libraries).await {
+        error!(message = "nesting folder tags failed", %error);
+    }
 
+    // Also covers images added in this scan, once their location is known from any source
+    if let Err(error) = backfill_places(state).await {
+        error!(message = "resolving places failed", %error);
+    }
+
+    info!(
+        message = "scan finished",
+        files = report.files,
+        added = report.added,
+        skipped = report.skipped,
+        failed = report.failed
+    );
+
+    Ok(report)
+}
+
+#[tracing::instrument(skip_all, fields(library = %library.name))]
+async fn verify_library(
+    state: &AppState,
+    library: &LibraryRoot,
+    report: &mut ScanReport,
+) -> Result<(), AppError> {
+    let mut ignore_rules = IgnoreRules::new(&library.path);
+    let mut sidecars = ExportSidecars::default();
     let mut files = Vec::new();
 
-    for entry in WalkDir::new(images_dir).into_iter().filter_map(|e| e.ok()) {
+    let entries = WalkDir::new(&library.path)
+        .into_iter()
+        .filter_entry(|entry| {
+            // Export sidecars are ignored as images, but note them on the way
+            if entry.file_type().is_file() {
+                sidecars.observe(entry.path());
+            }
+            let ignored = ignore_rules.is_ignored(entry.path(), entry.file_type().is_dir());
+            if ignored {
+                report.skipped += 1;
+            }
+            !ignored
+        });
+
+    for entry in entries.filter_map(|e| e.ok()) {
         if entry.file_type().is_file() {
             files.push(entry);
         }
     }
 
+    report.files += files.len();
+
     // For each image check that
     // - the image is in the DB
     // - all variants exist
     for file in files {
         // Is image in image table
-        let image_id = match image_indexed(state, &file).await {
-            Ok(Some(id)) => id,
-            Ok(None) => {
-                if let Ok(id) = add_image(state, &file).await {
+        let image_id = match image_indexed(state, &file, &sidecars).await {
+            Ok(Some(id)) => {
+                if let Err(error) = sync_sidecar(state, id, file.path()).await {
+                    error!(message="importing sidecar failed", file_name=%file.file_name().to_str().unwrap(), %error);
+                }
+                id
+            }
+            Ok(None) => match add_image(state, library, &file, &sidecars).await {
+                Ok(id) => {
+                    report.added += 1;
                     id
-                } else {
-                    error!(message="indexing image failed", file_name=%file.file_name().to_str().unwrap());
+                }
+                Err(error) => {
+                    report.failed += 1;
+                    error!(message="indexing image failed", file_name=%file.file_name().to_str().unwrap(), %error);
                     continue;
                 }
-            }
+            },
             Err(error) => {
+                report.failed += 1;
                 error!(message="indexing image failed", file_name=%file.file_name().to_str().unwrap(), %error);
                 continue;
             }
@@ -97,6 +219,22 @@ pub async fn verify_images(state: &AppState) -> Result<(), AppError> {
         }
     }
 
+    // Images indexed before roots were named belong to whichever root holds them, the
+    // separator keeps `/photos/family` from claiming `/photos/family2`
+    let assigned = query!(
+        "UPDATE image SET library = $1 WHERE library IS NULL AND starts_with(filename, $2 || '/');",
+        library.name,
+        library.path.to_str().map(|path| path.trim_end_matches('/'))
+    )
+    .execute(&state.pool)
+    .await?;
+    if assigned.rows_affected() > 0 {
+        info!(
+            message = "assigned images to library",
+            images = assigned.rows_affected()
+        );
+    }
+
     Ok(())
 }
 
@@ -131,8 +269,7 @@ async fn index_compressed_image(
         None => {
             info!(message = "variant is not indexed", %quality, image_id = %image_id);
 
-            let image = ImageReader::open(file.path())?.with_guessed_format()?;
-            let original_image = image.decode()?;
+            let original_image = decode_image_blocking(file.path()).await?;
 
             let dimensions = original_image.dimensions();
 
@@ -163,12 +300,11 @@ async fn index_compressed_image(
 
     info!(message = "variant file does not exist", %quality, image_id = %image_id);
 
-    let image = ImageReader::open(file.path())?.with_guessed_format()?;
-    let original_image = image.decode()?;
+    let original_image = decode_image_blocking(file.path()).await?;
 
     let dimensions = original_image.dimensions();
-    let width = (dimensions.0 as f32 / dimension_reduction as f32) as u32;
-    let height = (dimensions.1 as f32 / dimension_reduction as f32) as u32;
+    let width = (dimensions.0 as f32 / dimension_reduction) as u32;
+    let height = (dimensions.1 as f32 / dimension_reduction) as u32;
 
     let compressed_image = compress_image(&original_image, (width, height), 80)?;
     fs::write(file_path, &compressed_image)?;
@@ -178,50 +314,101 @@ async fn index_compressed_image(
     Ok(())
 }
 
-async fn add_image(state: &AppState, file: &DirEntry) -> Result<Uuid, AppError> {
+async fn add_image(
+    state: &AppState,
+    library: &LibraryRoot,
+    file: &DirEntry,
+    sidecars: &ExportSidecars,
+) -> Result<Uuid, AppError> {
     let image_id = Uuid::now_v7();
 
-    let images_dir = std::env::var("IMAGE_DIR").expect("IMAGE_DIR must be set");
-
-    let image = ImageReader::open(file.path())?.with_guessed_format()?;
-    let original_image = image.decode()?;
+    let format = source_format(file.path());
+    let video = match format {
+        SourceFormat::Video => match probe(file.path()).await {
+            Ok(info) => Some(info),
+            Err(error) => {
+                warn!(message = "probing video failed", file_name = ?file.file_name(), %error);
+                None
+            }
+        },
+        _ => None,
+    };
 
-    let dimensions = original_image.dimensions();
-    let aspect_ratio = dimensions.0 as f64 / dimensions.1 as f64;
+    // Placeholders of videos whose poster frame isn't decoded here are left to the backfill
+    let (dimensions, media_kind, duration, fingerprint, placeholder) = if format
+        == SourceFormat::Video
+    {
+        let probed = video
+            .as_ref()
+            .map(|info| (info.width, info.height))
+            .filter(|(width, height)| *width > 0 && *height > 0);
+        // Without a frame size from the container, measure the poster frame instead. Without
+        // ffmpeg the size stays unknown until the backfill gets a poster frame.
+        let (dimensions, placeholder) = match probed {
+            Some(dimensions) => (Some(dimensions), None),
+            None => match decode_image_blocking(file.path()).await {
+                Ok(poster) => (Some(poster.dimensions()), placeholder(&poster)),
+                Err(error) => {
+                    warn!(message = "extracting poster frame failed", file_name = ?file.file_name(), %error);
+                    (None, None)
+                }
+            },
+        };
+        (
+            dimensions,
+            "video",
+            video.as_ref().and_then(|info| info.duration),
+            None,
+            placeholder,
+        )
+    } else {
+        let original_image = decode_image_blocking(file.path()).await?;
+        (
+            Some(original_image.dimensions()),
+            "image",
+            None,
+            Some(color_fingerprint(&original_image)),
+            placeholder(&original_image),
+        )
+    };
+    let aspect_ratio = dimensions.map(|(width, height)| width as f64 / height as f64);
 
     // Generate object name for original
     let object_name_original = get_object_name();
 
+    // Location, description and albums kept by Google Takeout and Apple Photos exports
+    let export_metadata = read_export_metadata(file.path(), sidecars).unwrap_or_else(|error| {
+        warn!(message = "reading export sidecar failed", file_name = ?file.file_name(), %error);
+        None
+    });
+
     let mut tx = state.pool.begin().await?;
 
-    let (captured_at, exif) = get_capture_timestamp(&file)?;
+    let (captured_at, exif) =
+        get_capture_timestamp(file, format, video.as_ref(), export_metadata.as_ref())?;
+    let location = coordinates(&exif);
 
     // Insert image record within transaction
     let image_insert_result = query!(
-                    "INSERT INTO image (id, filename, captured_at, aspect_ratio, metadata) VALUES ($1, $2, $3, $4, $5);",
+                    "INSERT INTO image (id, filename, captured_at, aspect_ratio, metadata, media_kind, duration, library, dhash, histogram, placeholder, palette, latitude, longitude) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14);",
                     image_id,
                     file.path().to_str(),
                     captured_at,
                     aspect_ratio,
                     serde_json::to_string(&exif)?,
+                    media_kind,
+                    duration,
+                    library.name,
+                    fingerprint.as_ref().map(|f| f.fingerprint.dhash),
+                    fingerprint.as_ref().map(|f| f.fingerprint.histogram.as_slice()),
+                    placeholder,
+                    fingerprint.as_ref().map(|f| f.palette.as_slice()),
+                    location.map(|l| l.0),
+                    location.map(|l| l.1),
                 ).execute(&mut *tx).await;
 
-    // Add path segments to tags
-    // E.g. `/image_dir/france/lyon/test.jpeg` will get tags `france` and `lyon`
-    let relative = file
-        .path()
-        .strip_prefix(images_dir)
-        .expect("file does not have IMAGE_DIR prefix");
-    let relative = relative.parent().expect("file has no parent");
-    let folders: Vec<String> = relative
-        .components()
-        .map(|c| {
-            c.as_os_str()
-                .to_str()
-                .expect("Failed to turn Path component to string")
-                .to_string()
-        })
-        .collect();
+    // Add the folder path as a nested tag according to the root's policy
+    let folders = library.folder_tags(file.path());
     match add_tags(
         TagChangeRequest {
             image_ids: vec![image_id],
@@ -245,8 +432,23 @@ async fn add_image(state: &AppState, file: &DirEntry) -> Result<Uuid, AppError>
         return Err(AppError::DBError(e));
     }
 
-    // Insert original variant record within transaction
+    // Keywords, rating and caption curated in other tools
+    if media_kind == "image" {
+        match read_curated_metadata(file.path()) {
+            Ok(metadata) => apply_curated_metadata(image_id, metadata, &mut tx).await?,
+            Err(error) => {
+                warn!(message = "reading curated metadata failed", %image_id, %error)
+            }
+        }
+    }
+
+    if let Some(metadata) = export_metadata {
+        apply_export_metadata(image_id, metadata, &mut tx).await?;
+    }
+
+    // Insert original variant record within transaction, 0×0 if the size isn't known
     let original_quality = 100;
+    let dimensions = dimensions.unwrap_or_default();
     let variant_insert_result = query!(
                     "INSERT INTO variant (id, object_name, width, height, compression_quality, quality, version, image_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
                     Uuid::now_v7(), &object_name_original, dimensions.0 as i32, dimensions.1 as i32, original_quality, "original", 1 as i64, &image_id
@@ -266,30 +468,108 @@ async fn add_image(state: &AppState, file: &DirEntry) -> Result<Uuid, AppError>
     Ok(image_id)
 }
 
-async fn image_indexed(state: &AppState, file: &DirEntry) -> Result<Option<Uuid>, AppError> {
-    let (captured_at, _) = get_capture_timestamp(&file)?;
-
+async fn image_indexed(
+    state: &AppState,
+    file: &DirEntry,
+    sidecars: &ExportSidecars,
+) -> Result<Option<Uuid>, AppError> {
     #[derive(FromRow)]
     struct File {
         id: Uuid,
+        captured_at: String,
     }
 
-    let result = query_as!(
+    // Probing a video is slow, its path alone tells whether it's indexed
+    let format = source_format(file.path());
+    if format == SourceFormat::Video {
+        let result = query_as!(
+            File,
+            "SELECT id, captured_at FROM image WHERE filename = $1 ORDER BY id LIMIT 1;",
+            file.path().to_str()
+        )
+        .fetch_optional(&state.pool)
+        .await?;
+        if result.is_none() {
+            info!(message = "video is not indexed", file = ?file.file_name());
+        }
+        return Ok(result.map(|file| file.id));
+    }
+
+    // Dated from EXIF or the mtime, the export sidecar is only read when that's not enough
+    let (captured_at, _) = get_capture_timestamp(file, format, None, None)?;
+    let modified: Timestamp = file.metadata()?.modified()?.try_into()?;
+    let dated_by_mtime = captured_at == modified.to_string();
+    let has_sidecar = dated_by_mtime && sidecars.contains(file.path());
+
+    let indexed = query_as!(
         File,
-        "
-            SELECT id 
-            FROM image
-            WHERE filename = $1 AND captured_at = $2;
-        ",
-        file.path().to_str(),
-        captured_at
+        "SELECT id, captured_at FROM image WHERE filename = $1 ORDER BY id DESC;",
+        file.path().to_str()
     )
-    .fetch_optional(&state.pool)
+    .fetch_all(&state.pool)
     .await?;
 
-    if let Some(file) = result {
-        return Ok(Some(file.id));
-    };
+    // Images indexed before sub-second capture times were read only match to the second,
+    // move them to the exact time so bursts stack
+    if !dated_by_mtime && !indexed.iter().any(|image| image.captured_at == captured_at) {
+        let whole_second = Timestamp::from_second(captured_at.parse::<Timestamp>()?.as_second())?;
+        if let Some(image) = indexed
+            .iter()
+            .find(|image| image.captured_at == whole_second.to_string())
+        {
+            query!(
+                "UPDATE image SET captured_at = $2 WHERE id = $1;",
+                image.id,
+                captured_at
+            )
+            .execute(&state.pool)
+            .await?;
+            info!(message = "added sub-second capture time", image_id = %image.id);
+            return Ok(Some(image.id));
+        }
+    }
+
+    match indexed
+        .iter()
+        .find(|image| image.captured_at == captured_at)
+    {
+        Some(image) if !has_sidecar => return Ok(Some(image.id)),
+        // Images indexed before their export sidecar was read were dated by their mtime,
+        // move them to the capture time from the sidecar instead of indexing them again
+        Some(image) => {
+            let export_metadata =
+                read_export_metadata(file.path(), sidecars).unwrap_or_else(|error| {
+                    warn!(message = "reading export sidecar failed", file = ?file.path(), %error);
+                    None
+                });
+            let Some(sidecar_captured_at) = export_metadata.as_ref().and_then(|m| m.captured_at)
+            else {
+                return Ok(Some(image.id));
+            };
+
+            let mut tx = state.pool.begin().await?;
+            query!(
+                "UPDATE image SET captured_at = $2 WHERE id = $1;",
+                image.id,
+                sidecar_captured_at.to_string()
+            )
+            .execute(&mut *tx)
+            .await?;
+            if let Some(metadata) = export_metadata {
+                apply_export_metadata(image.id, metadata, &mut tx).await?;
+            }
+            tx.commit().await?;
+            info!(message = "re-dated image from export sidecar", image_id = %image.id);
+            return Ok(Some(image.id));
+        }
+        // Dated from its export sidecar when it was indexed
+        None if has_sidecar => {
+            if let Some(image) = indexed.first() {
+                return Ok(Some(image.id));
+            }
+        }
+        None => {}
+    }
 
     info!(message = "image is not indexed", file = %file.file_name()
         .to_str()
@@ -298,27 +578,69 @@ async fn image_indexed(state: &AppState, file: &DirEntry) -> Result<Option<Uuid>
     Ok(None)
 }
 
-fn get_capture_timestamp(file: &DirEntry) -> Result<(String, HashMap<String, String>), AppError> {
-    let last_modified = file.metadata()?.modified()?;
-    let exif = extract_exif(&file)?;
+/// When the image was taken, from EXIF or the video container, then from an export sidecar,
+/// and lastly from the file's mtime
+///
+/// `video` is the probed container of a video, if probing worked, and `export` the already
+/// read export sidecar.
+fn get_capture_timestamp(
+    file: &DirEntry,
+    format: SourceFormat,
+    video: Option<&VideoInfo>,
+    export: Option<&ExportMetadata>,
+) -> Result<(String, HashMap<String, String>), AppError> {
+    let fallback = match export.and_then(|metadata| metadata.captured_at) {
+        Some(captured_at) => captured_at,
+        None => file.metadata()?.modified()?.try_into()?,
+    };
+
+    if format == SourceFormat::Video {
+        let (created_at, metadata) = match video {
+            Some(info) => (info.created_at, info.metadata.clone()),
+            None => (None, HashMap::new()),
+        };
+        let ts = created_at.unwrap_or(fallback);
+        return Ok((ts.to_string(), metadata));
+    }
+
+    let exif = extract_exif(file)?;
     let timestamp;
 
     if let Some(captured_at) = exif.get("DateTimeOriginal") {
         let mut captured_at = strtime::parse("%Y-%m-%d %H:%M:%S", captured_at)?;
         captured_at.set_offset(Some(tz::offset(0)));
+        // Shots of a burst share the second, the fraction is stored in its own tag
+        let subsec = exif
+            .get("SubSecTimeOriginal")
+            .or_else(|| exif.get("SubSecTime"))
+            .and_then(|value| subsec_nanoseconds(value));
+        captured_at.set_subsec_nanosecond(subsec)?;
         timestamp = captured_at.to_timestamp()?.to_string();
     } else {
-        let ts: Timestamp = last_modified.try_into()?;
-        timestamp = ts.to_string();
+        timestamp = fallback.to_string();
     }
 
     Ok((timestamp, exif))
 }
 
+/// Nanoseconds of an EXIF `SubSecTime*` value, the digits following the decimal point
+fn subsec_nanoseconds(value: &str) -> Option<i32> {
+    let digits = value
+        .trim_matches('"')
+        .trim()
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .take(9)
+        .collect::<String>();
+    if digits.is_empty() {
+        return None;
+    }
+    format!("{:0<9}", digits).parse().ok()
+}
+
 fn extract_exif(file: &DirEntry) -> Result<HashMap<String, String>, AppError> {
-    let image = ImageReader::open(file.path())?.with_guessed_format()?;
     let mut exif_map = HashMap::new();
-    let exif = image.into_decoder()?.exif_metadata()?;
+    let exif = read_exif(file.path())?;
 
     if let Some(exif) = exif {
         let exif_reader = exif::Reader::new();
@@ -336,146 +658,520 @@ fn extract_exif(file: &DirEntry) -> Result<HashMap<String, String>, AppError> {
 pub struct Image {
     id: Uuid,
     captured_at: String,
-    aspect_ratio: f64,
+    /// Unknown for videos until a poster frame could be extracted
+    aspect_ratio: Option<f64>,
     tags: Option<Vec<String>>,
+    stack_id: Option<Uuid>,
+    stack_size: Option<i64>,
+    media_kind: String,
+    duration: Option<f64>,
+    library: Option<String>,
+    rating: Option<i32>,
+    caption: Option<String>,
+    /// BlurHash shown until the small variant has loaded
+    placeholder: Option<String>,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct SearchBody {
     query: String,
-    page: i64,
+    /// Page for offset pagination, ignored if a `cursor` is given
+    page: Option<i64>,
     limit: i64,
+    /// `next_cursor` of the previous response, to continue after its last image
+    cursor: Option<String>,
+    /// Count all matches, by default only done for the first request without a cursor
+    with_total: Option<bool>,
+    #[serde(default)]
+    sort: SortOrder,
+    /// Seed for the `random` order, a new one is picked if missing
+    seed: Option<String>,
+    /// Also count tags, dates, cameras and places of all matching images
+    #[serde(default)]
+    facets: bool,
+    /// Only return images from the library root with this name
+    library: Option<String>,
+    /// Only return images taken within `[west, south, east, north]`
+    bbox: Option<[f64; 4]>,
+    /// Taken at or after this date (`2023`, `2023-12`, `2023-12-24`) or RFC 3339 timestamp
+    from: Option<String>,
+    /// Taken up to the end of this date, or before this RFC 3339 timestamp
+    to: Option<String>,
+    year: Option<i32>,
+    month: Option<i32>,
+    day: Option<i32>,
+    /// Only images taken on today's month and day in earlier years, instead of `month` and `day`
+    #[serde(default)]
+    on_this_day: bool,
+}
+
+impl SearchBody {
+    /// Resolve `from` and `to` into the UTC range they cover
+    fn date_range(&self) -> Result<(Option<Timestamp>, Option<Timestamp>), AppError> {
+        let parse = |name: &str, value: &Option<String>| -> Result<_, AppError> {
+            let Some(value) = value else {
+                return Ok(None);
+            };
+            if let Ok(timestamp) = value.parse::<Timestamp>() {
+                return Ok(Some((timestamp, timestamp)));
+            }
+            parse_period(value).map(Some).ok_or_else(|| {
+                AppError::Text(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "invalid value '{}' for '{}', expected a date like 2023-12-24 or an RFC 3339 timestamp",
+                        value, name
+                    ),
+                )
+            })
+        };
+
+        let from = parse("from", &self.from)?.map(|(start, _)| start);
+        let to = parse("to", &self.to)?.map(|(_, end)| end);
+
+        if let (Some(from), Some(to)) = (from, to) {
+            if from >= to {
+                return Err(AppError::Text(
+                    StatusCode::BAD_REQUEST,
+                    "'from' must be before 'to'".to_string(),
+                ));
+            }
+        }
+        for (name, value, range) in [
+            ("year", self.year, 1..=9999),
+            ("month", self.month, 1..=12),
+            ("day", self.day, 1..=31),
+        ] {
+            if value.is_some_and(|value| !range.contains(&value)) {
+                return Err(AppError::Text(
+                    StatusCode::BAD_REQUEST,
+                    format!(
+                        "'{}' must be between {} and {}",
+                        name,
+                        range.start(),
+                        range.end()
+                    ),
+                ));
+            }
+        }
+
+        Ok((from, to))
+    }
 }
 
 #[derive(Serialize)]
 pub struct SearchResponse {
     images: Vec<Image>,
-    total: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
     has_more: bool,
+    next_cursor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    facets: Option<Facets>,
+}
+
+const MAX_SEARCH_LIMIT: i64 = 500;
+
+/// Position in search results: the sort keys and id of the last image returned
+#[derive(Serialize, Deserialize)]
+struct SearchCursor {
+    sort: SortOrder,
+    keys: Vec<String>,
+    id: Uuid,
+    /// Seed of the `random` order, so the following pages keep it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<String>,
+}
+
+#[derive(FromRow)]
+struct SortedImage {
+    #[sqlx(flatten)]
+    image: Image,
+    sort_keys: Vec<String>,
 }
 
 #[tracing::instrument(skip_all, fields(
     username = %account.username,
     query = %body.query,
-    page = %body.page,
+    page = ?body.page,
     limit = %body.limit,
+    sort = ?body.sort,
 ))]
 pub async fn search_images(
     account: AuthenticatedAccount,
     State(state): State<AppState>,
     body: Json<SearchBody>,
 ) -> Result<(StatusCode, Json<SearchResponse>), AppError> {
-    #[derive(FromRow, Debug)]
-    struct Tag {
-        id: Uuid,
-        description: String,
+    let filter = parse_query(&body.query)?;
+    let dates = body.date_range()?;
+
+    let cursor = body
+        .cursor
+        .as_deref()
+        .map(decode_cursor::<SearchCursor>)
+        .transpose()?;
+    let limit = body.limit;
+    if !(1..=MAX_SEARCH_LIMIT).contains(&limit) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_SEARCH_LIMIT),
+        ));
     }
-
-    let tags = query_as!(
-        Tag,
+    let offset = match cursor {
+        Some(_) => 0,
+        None => (body.page.unwrap_or(1).max(1) - 1)
+            .checked_mul(limit)
+            .ok_or_else(|| {
+                AppError::Text(StatusCode::BAD_REQUEST, "page is out of range".to_string())
+            })?,
+    };
+    let seed = (body.sort == SortOrder::Random).then(|| {
+        body.seed
+            .clone()
+            .or_else(|| cursor.as_ref().and_then(|cursor| cursor.seed.clone()))
+            .unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()))
+    });
+    let sort = Sort::new(body.sort, filter.as_ref(), seed.clone().unwrap_or_default());
+
+    let mut builder = QueryBuilder::new(
         "
-            SELECT tag.id, tag.description
-            FROM tag;
+            SELECT image.id, image.captured_at, image.aspect_ratio,
+                ARRAY(
+                    SELECT tag.path FROM image_tag JOIN tag ON image_tag.tag_id = tag.id
+                    WHERE image_tag.image_id = image.id
+                    ORDER BY tag.path
+                ) tags,
+                image.stack_id, (SELECT COUNT(*) FROM image member WHERE member.stack_id = image.stack_id) stack_size,
+                image.media_kind, image.duration, image.library, image.rating, image.caption, image.placeholder,
+                image.latitude, image.longitude, image.country, image.region, image.city,
         ",
-    )
-    .fetch_all(&state.pool)
-    .await?;
+    );
+    sort.push_select(&mut builder);
+    builder.push(
+        "
+            FROM image
+            LEFT JOIN stack ON image.stack_id = stack.id
+        ",
+    );
+    push_search_conditions(&mut builder, &body, filter.as_ref(), dates);
+    if let Some(cursor) = &cursor {
+        sort.push_after(&mut builder, cursor.sort, &cursor.keys, cursor.id)?;
+    }
+    sort.push_order_by(&mut builder);
+    // One more than asked for tells whether there is another page
+    builder
+        .push(" LIMIT ")
+        .push_bind(limit + 1)
+        .push(" OFFSET ")
+        .push_bind(offset);
+
+    let mut images = builder
+        .build_query_as::<SortedImage>()
+        .fetch_all(&state.pool)
+        .await?;
+
+    let has_more = images.len() as i64 > limit;
+    images.truncate(limit as usize);
+
+    let next_cursor = match images.last() {
+        Some(last) if has_more => Some(encode_cursor(&SearchCursor {
+            sort: body.sort,
+            keys: last.sort_keys.clone(),
+            id: last.image.id,
+            seed,
+        })?),
+        _ => None,
+    };
+    let images = images
+        .into_iter()
+        .map(|sorted| sorted.image)
+        .collect::<Vec<_>>();
+
+    let total = if body.with_total.unwrap_or(cursor.is_none()) {
+        let mut builder = QueryBuilder::new(
+            "
+                SELECT COUNT(*)
+                FROM image
+                LEFT JOIN stack ON image.stack_id = stack.id
+            ",
+        );
+        push_search_conditions(&mut builder, &body, filter.as_ref(), dates);
 
-    let tags = if body.query.len() < 3 {
-        vec![]
+        Some(builder.build_query_scalar().fetch_one(&state.pool).await?)
     } else {
-        let search_terms = body
-            .query
-            .split_whitespace()
-            .filter(|term| term.len() > 2)
-            .collect::<Vec<_>>();
-        let mut filtered_tags = vec![];
-        for term in &search_terms {
-            for tag in &tags {
-                if tag.description.contains(term) {
-                    filtered_tags.push(tag);
-                    break;
-                }
-            }
+        None
+    };
+
+    let facets = if body.facets {
+        let mut builder = QueryBuilder::new(
+            "
+                WITH RECURSIVE matched AS (
+                    SELECT image.id, image.taken_at, image.metadata, image.country, image.region, image.city
+                    FROM image
+                    LEFT JOIN stack ON image.stack_id = stack.id
+            ",
+        );
+        push_search_conditions(&mut builder, &body, filter.as_ref(), dates);
+        builder.push(")");
+
+        Some(load_facets(builder, &state.pool).await?)
+    } else {
+        None
+    };
+
+    info!(message = "load image list", number_of_files = images.len());
+
+    Ok((
+        StatusCode::OK,
+        Json(SearchResponse {
+            images,
+            total,
+            has_more,
+            next_cursor,
+            facets,
+        }),
+    ))
+}
+
+/// The `WHERE` clause shared by the search and its count, showing one image per stack
+fn push_search_conditions<'a>(
+    builder: &mut QueryBuilder<'a, Postgres>,
+    body: &'a SearchBody,
+    filter: Option<&Expr>,
+    (from, to): (Option<Timestamp>, Option<Timestamp>),
+) {
+    builder.push(" WHERE (stack.id IS NULL OR stack.representative_id = image.id)");
+
+    if let Some(library) = &body.library {
+        builder.push(" AND image.library = ").push_bind(library);
+    }
+
+    if let Some(bbox) = body.bbox.map(BoundingBox::from) {
+        builder
+            .push(" AND image.latitude BETWEEN ")
+            .push_bind(bbox.south)
+            .push(" AND ")
+            .push_bind(bbox.north);
+        // `west` is larger than `east` when the box crosses the antimeridian
+        if bbox.west <= bbox.east {
+            builder
+                .push(" AND image.longitude BETWEEN ")
+                .push_bind(bbox.west)
+                .push(" AND ")
+                .push_bind(bbox.east);
+        } else {
+            builder
+                .push(" AND (image.longitude >= ")
+                .push_bind(bbox.west)
+                .push(" OR image.longitude <= ")
+                .push_bind(bbox.east)
+                .push(")");
         }
-        filtered_tags
+    }
+
+    if let Some(from) = from {
+        builder
+            .push(" AND image.taken_at >= ")
+            .push_bind(from.to_string())
+            .push("::text::timestamptz");
+    }
+    if let Some(to) = to {
+        builder
+            .push(" AND image.taken_at < ")
+            .push_bind(to.to_string())
+            .push("::text::timestamptz");
+    }
+
+    // Capture times without a zone are stored as UTC, so UTC gives the calendar date they show
+    let today = Timestamp::now().to_zoned(TimeZone::UTC).date();
+    let (month, day) = if body.on_this_day {
+        (Some(today.month() as i32), Some(today.day() as i32))
+    } else {
+        (body.month, body.day)
     };
+    // Years as a range and months and days as in the index on `taken_at`
+    if let Some(year) = body.year {
+        builder
+            .push(" AND image.taken_at >= ")
+            .push_bind(format!("{:04}-01-01T00:00:00Z", year))
+            .push("::text::timestamptz AND image.taken_at < ")
+            .push_bind(format!("{:04}-01-01T00:00:00Z", year + 1))
+            .push("::text::timestamptz");
+    }
+    for (field, value) in [("MONTH", month), ("DAY", day)] {
+        if let Some(value) = value {
+            builder
+                .push(format!(
+                    " AND EXTRACT({} FROM image.taken_at AT TIME ZONE 'UTC') = ",
+                    field
+                ))
+                .push_bind(value);
+        }
+    }
+    if body.on_this_day {
+        builder
+            .push(" AND image.taken_at < ")
+            .push_bind(format!("{:04}-01-01T00:00:00Z", today.year()))
+            .push("::text::timestamptz");
+    }
+
+    if let Some(filter) = filter {
+        builder.push(" AND ");
+        filter.push_sql(builder);
+    }
+}
+
+const DEFAULT_SIMILAR_LIMIT: i64 = 50;
+const MAX_SIMILAR_LIMIT: i64 = 200;
+/// Images whose hashes differ in more bits show a different scene, whatever their colors
+const MAX_SIMILAR_HASH_DISTANCE: i64 = 20;
+/// Candidates closest by hash that are ranked by the full distance
+const MAX_SIMILAR_CANDIDATES: i64 = 1000;
+
+#[derive(Deserialize, Debug)]
+pub struct SimilarParams {
+    page: Option<i64>,
+    limit: Option<i64>,
+}
+
+/// Images closest to the given one by difference hash and color histogram, most similar first
+///
+/// The database narrows the library down to the images closest by hash, only those are ranked.
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    image_id = %image_id,
+))]
+pub async fn get_similar_images(
+    account: AuthenticatedAccount,
+    Path(image_id): Path<Uuid>,
+    params: Query<SimilarParams>,
+    State(state): State<AppState>,
+) -> Result<Json<SearchResponse>, AppError> {
+    #[derive(FromRow)]
+    struct Candidate {
+        id: Uuid,
+        dhash: Option<i64>,
+        histogram: Option<Vec<f32>>,
+        stack_id: Option<Uuid>,
+    }
 
-    if tags.is_empty() && body.query.len() >= 3 {
-        return Ok((
-            StatusCode::OK,
-            Json(SearchResponse {
-                images: vec![],
-                total: 0,
-                has_more: false,
-            }),
+    let limit = params.limit.unwrap_or(DEFAULT_SIMILAR_LIMIT);
+    if !(1..=MAX_SIMILAR_LIMIT).contains(&limit) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_SIMILAR_LIMIT),
         ));
     }
+    let Some(offset) = (params.page.unwrap_or(1).max(1) - 1).checked_mul(limit) else {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "page is out of range".to_string(),
+        ));
+    };
 
-    let limit = body.limit;
-    let offset = (body.page - 1) * limit;
+    let result = query_as!(
+        Candidate,
+        "SELECT id, dhash, histogram, stack_id FROM image WHERE id = $1;",
+        image_id
+    )
+    .fetch_optional(&state.pool)
+    .await?;
 
-    let mut images = query_as!(
-        Image,
+    let Some(image) = result else {
+        warn!(message = "image doesn't exist");
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    };
+
+    let (Some(dhash), Some(histogram)) = (image.dhash, image.histogram) else {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            "image has no fingerprint yet".to_string(),
+        ));
+    };
+    let target = Fingerprint { dhash, histogram };
+
+    // Other members of the image's own stack are the same shot, not similar ones
+    let candidates = query_as!(
+        Candidate,
         "
-            SELECT image.id, image.captured_at, image.aspect_ratio, ARRAY_REMOVE( ARRAY_AGG(tag.description), NULL) tags
+            SELECT image.id, image.dhash, image.histogram, image.stack_id
             FROM image
-            LEFT JOIN image_tag ON image.id = image_tag.image_id
-            LEFT JOIN tag ON image_tag.tag_id = tag.id
-            GROUP BY image.id
-            HAVING ARRAY_AGG(tag_id::text) @> ARRAY[$1::text[]]
-            ORDER BY image.captured_at DESC
-            LIMIT $2
-            OFFSET $3;
+            LEFT JOIN stack ON image.stack_id = stack.id
+            WHERE (stack.id IS NULL OR stack.representative_id = image.id)
+                AND image.id <> $1
+                AND ($2::uuid IS NULL OR image.stack_id IS DISTINCT FROM $2)
+                AND image.histogram IS NOT NULL
+                AND BIT_COUNT((image.dhash # $3)::bit(64)) <= $4
+            ORDER BY BIT_COUNT((image.dhash # $3)::bit(64)), image.id
+            LIMIT $5;
         ",
-        &tags.iter().map(|tag| tag.id.to_string()).collect::<Vec<_>>(),
-        limit,
-        offset
+        image_id,
+        image.stack_id,
+        dhash,
+        MAX_SIMILAR_HASH_DISTANCE,
+        MAX_SIMILAR_CANDIDATES
     )
     .fetch_all(&state.pool)
     .await?;
 
-    #[derive(FromRow)]
-    struct Count {
-        count: Option<i64>,
-    }
+    let mut ranked = candidates
+        .into_iter()
+        .filter_map(|candidate| {
+            let fingerprint = Fingerprint {
+                dhash: candidate.dhash?,
+                histogram: candidate.histogram?,
+            };
+            Some((visual_distance(&target, &fingerprint), candidate.id))
+        })
+        .collect::<Vec<_>>();
+    ranked.sort_by(|a, b| a.0.total_cmp(&b.0));
 
-    let total_count = query_as!(
-        Count,
+    let ids = ranked
+        .iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|(_, id)| *id)
+        .collect::<Vec<_>>();
+
+    let mut images = query_as!(
+        Image,
         "
-        SELECT COUNT(DISTINCT image.id) as count
-        FROM image
-        LEFT JOIN image_tag ON image.id = image_tag.image_id
-        WHERE image.id IN (
-            SELECT image_id 
-            FROM image_tag
-            GROUP BY image_id
-            HAVING ARRAY_AGG(tag_id::text) @> ARRAY[$1::text[]]
-        );
-        ;
-    ",
-        &tags
-            .iter()
-            .map(|tag| tag.id.to_string())
-            .collect::<Vec<_>>()
+            SELECT image.id, image.captured_at, image.aspect_ratio, ARRAY_REMOVE( ARRAY_AGG(tag.path), NULL) tags,
+                image.stack_id, (SELECT COUNT(*) FROM image member WHERE member.stack_id = image.stack_id) stack_size,
+                image.media_kind, image.duration, image.library, image.rating, image.caption, image.placeholder,
+                image.latitude, image.longitude, image.country, image.region, image.city
+            FROM image
+            LEFT JOIN image_tag ON image.id = image_tag.image_id
+            LEFT JOIN tag ON image_tag.tag_id = tag.id
+            WHERE image.id = ANY($1)
+            GROUP BY image.id;
+        ",
+        &ids
     )
-    .fetch_one(&state.pool)
+    .fetch_all(&state.pool)
     .await?;
 
-    images.sort_by(|a, b| b.captured_at.cmp(&a.captured_at));
+    images.sort_by_key(|image| ids.iter().position(|id| *id == image.id));
 
-    info!(message = "load image list", number_of_files = images.len());
+    info!(
+        message = "found similar images",
+        number_of_files = images.len()
+    );
 
-    let total = total_count.count.unwrap_or_default();
+    let total = ranked.len() as i64;
 
-    Ok((
-        StatusCode::OK,
-        Json(SearchResponse {
-            images,
-            total,
-            has_more: body.page * body.limit <= total,
-        }),
-    ))
+    Ok(Json(SearchResponse {
+        has_more: offset.saturating_add(images.len() as i64) < total,
+        images,
+        total: Some(total),
+        next_cursor: None,
+        facets: None,
+    }))
 }
 
 #[derive(Deserialize, Debug)]
@@ -493,6 +1189,7 @@ pub async fn get_image(
     Path(image_id): Path<Uuid>,
     params: Query<QueryParams>,
     State(state): State<AppState>,
+    request: Request,
 ) -> Result<Response, AppError> {
     info!(message = "get image");
 
@@ -535,16 +1232,17 @@ pub async fn get_image(
         return Err(AppError::Status(StatusCode::NOT_FOUND));
     }
 
-    let file = File::open(&file_path).await?;
+    // Variants are always JPEG, originals are served untouched in their own format.
+    // Serving through `ServeFile` answers Range requests, which video playback relies on.
+    let mut serve_file = if params.quality == "original" {
+        ServeFile::new(&file_path)
+    } else {
+        ServeFile::new_with_mime(&file_path, &mime::IMAGE_JPEG)
+    };
 
-    let stream = ReaderStream::new(file);
-    let body = Body::from_stream(stream);
+    let response = serve_file.try_call(request).await?;
 
-    Ok(Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, "image/jpeg")
-        .body(body)
-        .unwrap())
+    Ok(response.map(Body::new))
 }
 
 #[tracing::instrument(skip_all, fields(
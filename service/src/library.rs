@@ -0,0 +1,177 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use axum::http::StatusCode;
+use serde::Deserialize;
+
+use crate::error::AppError;
+
+/// Name of the root used when only `IMAGE_DIR` is configured
+pub const DEFAULT_LIBRARY_NAME: &str = "default";
+
+/// A folder tree the scanner indexes, e.g. "family" on one volume and "archive" on another
+#[derive(Deserialize, Clone, Debug)]
+pub struct LibraryRoot {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub folder_tags: FolderTagPolicy,
+}
+
+/// How the folders an image sits in are turned into tags
+#[derive(Deserialize, Clone, Debug)]
+pub struct FolderTagPolicy {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Only the first `max_depth` folders below the root become tags
+    pub max_depth: Option<usize>,
+    /// Prepended to the top-level folder tag, e.g. `archive-` for `archive-france/lyon`
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Default for FolderTagPolicy {
+    fn default() -> Self {
+        FolderTagPolicy {
+            enabled: true,
+            max_depth: None,
+            prefix: String::new(),
+        }
+    }
+}
+
+/// Read the library roots from the JSON file at `LIBRARY_CONFIG`
+///
+/// Without it, `IMAGE_DIR` is the single writable root with folder tagging enabled. Roots
+/// need unique names and must not contain one another, else a file would belong to two.
+pub fn load_library_roots() -> Result<Vec<LibraryRoot>, AppError> {
+    let Ok(config) = std::env::var("LIBRARY_CONFIG") else {
+        let images_dir = std::env::var("IMAGE_DIR").expect("IMAGE_DIR must be set");
+        return Ok(vec![LibraryRoot {
+            name: DEFAULT_LIBRARY_NAME.to_string(),
+            path: PathBuf::from(images_dir),
+            read_only: false,
+            folder_tags: FolderTagPolicy::default(),
+        }]);
+    };
+
+    let roots: Vec<LibraryRoot> = serde_json::from_str(&fs::read_to_string(config)?)?;
+    validate_library_roots(&roots)?;
+
+    Ok(roots)
+}
+
+fn validate_library_roots(roots: &[LibraryRoot]) -> Result<(), AppError> {
+    // Symlinks and `..` would hide an overlap, paths that don't exist yet are compared as is
+    let paths = roots
+        .iter()
+        .map(|root| fs::canonicalize(&root.path).unwrap_or_else(|_| root.path.clone()))
+        .collect::<Vec<_>>();
+
+    for (index, root) in roots.iter().enumerate() {
+        for (other, other_path) in roots.iter().zip(&paths).skip(index + 1) {
+            if root.name == other.name {
+                return Err(AppError::Text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("library root name '{}' is used twice", root.name),
+                ));
+            }
+            if paths[index].starts_with(other_path) || other_path.starts_with(&paths[index]) {
+                return Err(AppError::Text(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("library roots '{}' and '{}' overlap", root.name, other.name),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl LibraryRoot {
+    /// Tag path derived from the folders between the root and `file`
+    ///
+    /// E.g. `/image_dir/france/lyon/test.jpeg` will get the tag `france/lyon`, a child of
+    /// `france`
+    pub fn folder_tags(&self, file: &Path) -> Vec<String> {
+        if !self.folder_tags.enabled {
+            return vec![];
+        }
+
+        let Some(folder) = file
+            .strip_prefix(&self.path)
+            .ok()
+            .and_then(|relative| relative.parent())
+        else {
+            return vec![];
+        };
+
+        let path = folder
+            .components()
+            .take(self.folder_tags.max_depth.unwrap_or(usize::MAX))
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+
+        if path.is_empty() {
+            vec![]
+        } else {
+            vec![format!("{}{}", self.folder_tags.prefix, path)]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root(name: &str, path: &str) -> LibraryRoot {
+        LibraryRoot {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+            read_only: false,
+            folder_tags: FolderTagPolicy::default(),
+        }
+    }
+
+    #[test]
+    fn prefix_only_applies_to_the_top_level_folder() {
+        let mut archive = root("archive", "/archive");
+        archive.folder_tags.prefix = "archive-".to_string();
+
+        assert_eq!(
+            archive.folder_tags(Path::new("/archive/france/lyon/test.jpeg")),
+            vec!["archive-france/lyon"]
+        );
+        assert!(archive
+            .folder_tags(Path::new("/archive/test.jpeg"))
+            .is_empty());
+    }
+
+    #[test]
+    fn roots_need_unique_names() {
+        let roots = [root("family", "/family"), root("family", "/archive")];
+
+        assert!(validate_library_roots(&roots).is_err());
+    }
+
+    #[test]
+    fn roots_must_not_overlap() {
+        let nested = [root("photos", "/photos"), root("family", "/photos/family")];
+        let siblings = [
+            root("photos", "/photos"),
+            root("archive", "/photos-archive"),
+        ];
+
+        assert!(validate_library_roots(&nested).is_err());
+        assert!(validate_library_roots(&siblings).is_ok());
+    }
+}
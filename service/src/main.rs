@@ -1,25 +1,49 @@
 mod auth;
+mod color;
+mod cursor;
+mod decode;
+mod duplicates;
 mod error;
+mod facets;
+mod fingerprint;
+mod geo;
+mod geocoder;
+mod geotag;
+mod ignore_rules;
 mod image;
+mod library;
+mod map;
+mod photo_export;
+mod query;
+mod sidecar;
+mod sort;
 mod spa;
+mod stack;
 mod tag;
+mod tag_alias;
 mod utils;
+mod video;
+mod xmp;
 
-use std::{env, time::Duration};
+use std::{env, sync::Arc, time::Duration};
 
 use auth::login;
 use axum::{
     body::Body,
     extract::DefaultBodyLimit,
     http::{Request, Response, StatusCode},
-    routing::{delete, get, post},
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use dotenv::dotenv;
 use error::AppError;
+use geocoder::Geocoder;
+use geotag::geotag_from_gpx;
 use image::search_images;
+use library::{load_library_roots, LibraryRoot};
 use opentelemetry::{global, trace::TracerProvider};
 use opentelemetry_sdk::trace::SdkTracerProvider;
+use sidecar::{rewrite_all_sidecars, SidecarWriter};
 use spa::static_handler;
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 use tag::remove_tags;
@@ -31,13 +55,20 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 use uuid::Uuid;
 
 use crate::{
-    image::{get_image, get_image_metadata, scan_disk},
-    tag::add_tags_handler,
+    duplicates::get_duplicates,
+    image::{get_image, get_image_metadata, get_similar_images, scan_disk},
+    map::get_map_clusters,
+    stack::get_stack,
+    tag::{add_tags_handler, list_tags, merge_tags, rename_tag, suggest_tags},
+    tag_alias::{create_alias, delete_alias, list_aliases, update_alias},
 };
 
 #[derive(Clone, Debug)]
 pub struct AppState {
     pool: Pool<Postgres>,
+    libraries: Arc<Vec<LibraryRoot>>,
+    sidecars: Option<SidecarWriter>,
+    geocoder: Option<Arc<Geocoder>>,
 }
 
 #[tokio::main]
@@ -82,7 +113,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!(message = "Migrations applied");
 
-    let state = AppState { pool: pool.clone() };
+    let libraries = load_library_roots()?;
+
+    info!(
+        message = "Loaded library roots",
+        libraries = ?libraries.iter().map(|l| &l.name).collect::<Vec<_>>()
+    );
+
+    let libraries = Arc::new(libraries);
+    let sidecars = SidecarWriter::start(pool.clone(), libraries.clone());
+
+    if sidecars.is_some() {
+        info!(message = "Writing tags back to XMP sidecars");
+    }
+
+    let geocoder = Geocoder::load()?.map(Arc::new);
+
+    let state = AppState {
+        pool: pool.clone(),
+        libraries,
+        sidecars,
+        geocoder,
+    };
 
     info!(message = "Starting to scan for trigger file to start disk scan");
 
@@ -98,8 +150,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .route("/api/images/search", post(search_images))
         .route("/api/images/{id}", get(get_image))
         .route("/api/images/{id}/metadata", get(get_image_metadata))
+        .route("/api/images/{id}/similar", get(get_similar_images))
+        .route("/api/stacks/{id}", get(get_stack))
+        .route("/api/duplicates", get(get_duplicates))
+        .route("/api/map/clusters", get(get_map_clusters))
+        .route("/api/geotag/gpx", post(geotag_from_gpx))
+        .route("/api/tags", get(list_tags))
         .route("/api/tags", post(add_tags_handler))
         .route("/api/tags", delete(remove_tags))
+        .route("/api/tags/suggest", get(suggest_tags))
+        .route("/api/tags/merge", post(merge_tags))
+        .route("/api/tags/{id}", patch(rename_tag))
+        .route("/api/tags/aliases", get(list_aliases))
+        .route("/api/tags/aliases", post(create_alias))
+        .route("/api/tags/aliases/{id}", put(update_alias))
+        .route("/api/tags/aliases/{id}", delete(delete_alias))
+        .route("/api/admin/sidecars/rewrite", post(rewrite_all_sidecars))
         .fallback(static_handler)
         .with_state(state)
         .layer(
@@ -0,0 +1,96 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query_as, FromRow};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{auth::AuthenticatedAccount, error::AppError, geo::BoundingBox, AppState};
+
+const MAX_ZOOM: u8 = 22;
+/// Web Mercator ends here, the poles would be infinitely far away
+const MAX_MERCATOR_LATITUDE: f64 = 85.0511;
+/// Clusters per 256px map tile along each axis, so markers stay about 64px apart
+const CLUSTERS_PER_TILE: f64 = 4.0;
+
+#[derive(Deserialize, Debug)]
+pub struct ClustersParams {
+    /// `west,south,east,north` of the visible map
+    bbox: String,
+    zoom: u8,
+    library: Option<String>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct MapCluster {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    count: Option<i64>,
+    /// The most recent image of the cluster
+    representative_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct ClustersResponse {
+    clusters: Vec<MapCluster>,
+}
+
+/// Group the located images inside `bbox` into a grid sized for `zoom`
+///
+/// The grid is laid out in Web Mercator, so cells are square on the map at any latitude.
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    bbox = %params.bbox,
+    zoom = %params.zoom,
+))]
+pub async fn get_map_clusters(
+    account: AuthenticatedAccount,
+    params: Query<ClustersParams>,
+    State(state): State<AppState>,
+) -> Result<Json<ClustersResponse>, AppError> {
+    let bbox: BoundingBox = params.bbox.parse()?;
+    if params.zoom > MAX_ZOOM {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("zoom must be at most {}", MAX_ZOOM),
+        ));
+    }
+
+    // Web maps show 360 degrees of longitude on 2^zoom tiles
+    let cell_size = 360.0 / 2f64.powi(params.zoom as i32) / CLUSTERS_PER_TILE;
+
+    let clusters = query_as!(
+        MapCluster,
+        "
+            SELECT AVG(image.latitude) latitude, AVG(image.longitude) longitude, COUNT(*) count,
+                (ARRAY_AGG(image.id ORDER BY image.captured_at DESC))[1] representative_id
+            FROM image
+            LEFT JOIN stack ON image.stack_id = stack.id
+            WHERE (stack.id IS NULL OR stack.representative_id = image.id)
+                AND image.latitude BETWEEN $3::float8 AND $5::float8
+                AND CASE WHEN $2::float8 <= $4::float8
+                    THEN image.longitude BETWEEN $2 AND $4
+                    ELSE image.longitude >= $2 OR image.longitude <= $4
+                END
+                AND ($6::text IS NULL OR image.library = $6)
+            GROUP BY FLOOR(image.longitude / $1),
+                FLOOR(DEGREES(LN(TAN(PI() / 4 + RADIANS(LEAST(GREATEST(image.latitude, -$7::float8), $7)) / 2))) / $1);
+        ",
+        cell_size,
+        bbox.west,
+        bbox.south,
+        bbox.east,
+        bbox.north,
+        params.library,
+        MAX_MERCATOR_LATITUDE
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    info!(message = "clustered map", clusters = clusters.len());
+
+    Ok(Json(ClustersResponse { clusters }))
+}
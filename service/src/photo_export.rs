@@ -0,0 +1,301 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use jiff::{fmt::strtime, tz, Timestamp};
+use serde_json::Value;
+use sqlx::{query, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    tag::{add_tags, TagChangeRequest},
+};
+
+/// What Google Takeout and Apple Photos exporters keep next to the photo instead of in it
+#[derive(Debug, Default)]
+pub struct ExportMetadata {
+    pub captured_at: Option<Timestamp>,
+    pub location: Option<(f64, f64)>,
+    pub description: Option<String>,
+    pub keywords: Vec<String>,
+}
+
+/// The `*.json` files of each folder of a library, collected once per scan
+///
+/// Looking sidecars up here spares listing the folder again for every image in it.
+#[derive(Default)]
+pub struct ExportSidecars {
+    names: HashMap<PathBuf, BTreeSet<String>>,
+    albums: HashMap<PathBuf, String>,
+}
+
+impl ExportSidecars {
+    /// Record `path` if it's a JSON file, reading the album title from `metadata.json`
+    pub fn observe(&mut self, path: &Path) {
+        let (Some(folder), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str()))
+        else {
+            return;
+        };
+        if !name.ends_with(".json") {
+            return;
+        }
+
+        if name == "metadata.json" {
+            if let Some(title) = album_title(path) {
+                self.albums.insert(folder.to_path_buf(), title);
+            }
+        }
+        self.names
+            .entry(folder.to_path_buf())
+            .or_default()
+            .insert(name.to_string());
+    }
+
+    /// Whether any export sidecar of `file` exists
+    pub fn contains(&self, file: &Path) -> bool {
+        self.find(file).is_some()
+    }
+
+    /// Find the sidecar among the names Takeout has used over the years
+    ///
+    /// - `IMG_0001.jpg.json` and `IMG_0001.jpg.supplemental-metadata.json`, the latter often
+    ///   truncated to e.g. `IMG_0001.jpg.supplemental-met.json`
+    /// - `IMG_0001.jpg.json` for the edited `IMG_0001-edited.jpg`
+    /// - `IMG_0001.jpg(1).json` for the duplicate `IMG_0001(1).jpg`
+    /// - names cut off after 46 characters, e.g. `a_very_long_file_name….json`
+    fn find(&self, file: &Path) -> Option<PathBuf> {
+        let folder = file.parent()?;
+        let names = self.names.get(folder)?;
+        let name = file.file_name()?.to_str()?;
+        let stem = file.file_stem()?.to_str()?;
+        let extension = file
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| format!(".{}", e))
+            .unwrap_or_default();
+
+        let mut candidates = vec![
+            format!("{}.json", name),
+            format!("{}.supplemental-metadata.json", name),
+        ];
+
+        if let Some(original) = stem.strip_suffix("-edited") {
+            candidates.push(format!("{}{}.json", original, extension));
+            candidates.push(format!(
+                "{}{}.supplemental-metadata.json",
+                original, extension
+            ));
+        }
+
+        if let Some((original, counter)) = stem
+            .strip_suffix(')')
+            .and_then(|stem| stem.rsplit_once('('))
+        {
+            candidates.push(format!("{}{}({}).json", original, extension, counter));
+            candidates.push(format!(
+                "{}{}.supplemental-metadata({}).json",
+                original, extension, counter
+            ));
+        }
+
+        candidates.push(format!(
+            "{}.json",
+            name.chars().take(46).collect::<String>()
+        ));
+
+        // Truncated variants of `.supplemental-metadata.json` sort right after the prefix
+        let prefix = format!("{}.s", name);
+        let truncated = names
+            .range(prefix.clone()..)
+            .take_while(|n| n.starts_with(&prefix))
+            .next()
+            .cloned();
+
+        candidates
+            .into_iter()
+            .find(|candidate| names.contains(candidate))
+            .or(truncated)
+            .map(|found| folder.join(found))
+    }
+}
+
+/// Read the JSON sidecar of `file` as written by Google Takeout or by `osxphotos --sidecar json`
+pub fn read_export_metadata(
+    file: &Path,
+    sidecars: &ExportSidecars,
+) -> Result<Option<ExportMetadata>, AppError> {
+    let Some(sidecar) = sidecars.find(file) else {
+        return Ok(None);
+    };
+
+    let json: Value = serde_json::from_str(&fs::read_to_string(sidecar)?)?;
+
+    let mut metadata = match &json {
+        // exiftool-style list with a single object, as written for Apple Photos exports
+        Value::Array(entries) => match entries.first() {
+            Some(entry) => parse_exiftool(entry),
+            None => return Ok(None),
+        },
+        Value::Object(_) => parse_takeout(&json),
+        _ => return Ok(None),
+    };
+
+    if let Some(album) = file.parent().and_then(|folder| sidecars.albums.get(folder)) {
+        metadata.keywords.push(album.to_lowercase());
+    }
+
+    Ok(Some(metadata))
+}
+
+/// Set location and description of the image and add its keywords and album as tags
+pub async fn apply_export_metadata<'c>(
+    image_id: Uuid,
+    metadata: ExportMetadata,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    if !metadata.keywords.is_empty() {
+        add_tags(
+            TagChangeRequest {
+                image_ids: vec![image_id],
+                tags: metadata.keywords,
+            },
+            tx,
+        )
+        .await?;
+    }
+
+    query!(
+        "
+            UPDATE image
+            SET latitude = COALESCE($2, latitude), longitude = COALESCE($3, longitude), caption = COALESCE(caption, $4)
+            WHERE id = $1;
+        ",
+        image_id,
+        metadata.location.map(|l| l.0),
+        metadata.location.map(|l| l.1),
+        metadata.description
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Takeout puts a `metadata.json` with the album title into album folders
+fn album_title(path: &Path) -> Option<String> {
+    let metadata = fs::read_to_string(path).ok()?;
+    let metadata: Value = serde_json::from_str(&metadata).ok()?;
+
+    metadata["title"]
+        .as_str()
+        .or(metadata["albumData"]["title"].as_str())
+        .map(str::trim)
+        .filter(|title| !title.is_empty())
+        .map(str::to_string)
+}
+
+fn parse_takeout(json: &Value) -> ExportMetadata {
+    let captured_at = json["photoTakenTime"]["timestamp"]
+        .as_str()
+        .and_then(|t| t.parse::<i64>().ok())
+        .and_then(|t| Timestamp::from_second(t).ok());
+
+    // Takeout writes 0.0/0.0 for photos without a location
+    let location = ["geoData", "geoDataExif"].iter().find_map(|key| {
+        let latitude = json[key]["latitude"].as_f64()?;
+        let longitude = json[key]["longitude"].as_f64()?;
+        (latitude != 0.0 || longitude != 0.0).then_some((latitude, longitude))
+    });
+
+    let description = json["description"]
+        .as_str()
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(str::to_string);
+
+    let keywords = json["people"]
+        .as_array()
+        .map(|people| {
+            people
+                .iter()
+                .filter_map(|person| person["name"].as_str())
+                .map(str::to_lowercase)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    ExportMetadata {
+        captured_at,
+        location,
+        description,
+        keywords,
+    }
+}
+
+fn parse_exiftool(json: &Value) -> ExportMetadata {
+    let captured_at = json["EXIF:DateTimeOriginal"]
+        .as_str()
+        .and_then(|date| strtime::parse("%Y:%m:%d %H:%M:%S", date).ok())
+        .and_then(|mut date| {
+            let offset = json["EXIF:OffsetTimeOriginal"]
+                .as_str()
+                .and_then(|offset| strtime::parse("%:z", offset).ok())
+                .and_then(|offset| offset.offset())
+                .unwrap_or(tz::offset(0));
+            date.set_offset(Some(offset));
+            date.to_timestamp().ok()
+        });
+
+    let coordinate = |name: &str, negative_ref: &str| {
+        if let Some(signed) = json[format!("Composite:{}", name)].as_f64() {
+            return Some(signed);
+        }
+        let value = json[format!("EXIF:{}", name)].as_f64()?;
+        let reference = json[format!("EXIF:{}Ref", name)]
+            .as_str()
+            .unwrap_or_default();
+        Some(if reference.starts_with(negative_ref) {
+            -value.abs()
+        } else {
+            value
+        })
+    };
+    let location = coordinate("GPSLatitude", "S").zip(coordinate("GPSLongitude", "W"));
+
+    let description = [
+        "XMP:Description",
+        "EXIF:ImageDescription",
+        "IPTC:Caption-Abstract",
+    ]
+    .iter()
+    .find_map(|key| json[key].as_str())
+    .map(str::trim)
+    .filter(|d| !d.is_empty())
+    .map(str::to_string);
+
+    let mut keywords = vec![];
+    for key in ["XMP:Subject", "IPTC:Keywords", "XMP:PersonInImage"] {
+        match &json[key] {
+            Value::String(keyword) => keywords.push(keyword.to_lowercase()),
+            Value::Array(values) => keywords.extend(
+                values
+                    .iter()
+                    .filter_map(|v| v.as_str())
+                    .map(str::to_lowercase),
+            ),
+            _ => {}
+        }
+    }
+    keywords.sort();
+    keywords.dedup();
+
+    ExportMetadata {
+        captured_at,
+        location,
+        description,
+        keywords,
+    }
+}
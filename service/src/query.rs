@@ -0,0 +1,698 @@
+use axum::http::StatusCode;
+use jiff::{civil::Date, tz::TimeZone, Timestamp, ToSpan};
+use sqlx::{Postgres, QueryBuilder};
+
+use crate::{
+    color::{parse_color_filter, ColorFilter},
+    error::AppError,
+    tag::normalize_tag_path,
+};
+
+/// Shorter words would match too many tags by prefix
+const MIN_PREFIX_LENGTH: usize = 3;
+
+/// A parsed search query
+///
+/// Terms next to each other must all match, `OR` binds weaker than that and `NOT` or a
+/// leading `-` negates the following term. Parentheses group terms.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Filter(Filter),
+}
+
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// A bare word, matching tags exactly, by alias, by prefix or by trigram similarity to catch
+    /// typos, along with their descendants. Negated words become `Tag`s, so `-cat` keeps `cats`.
+    Word(String),
+    /// A `"quoted phrase"` or `tag:…`, matching a tag name, path or alias exactly, ignoring
+    /// case, along with its descendants
+    Tag(String),
+    /// `camera:x100v`, matching part of the EXIF make and model
+    Camera(String),
+    /// `year:2023`
+    Year(i32),
+    /// `before:2020-05`, taken before the start of the day, month or year
+    Before(Timestamp),
+    /// `after:2020-05`, taken after the end of the day, month or year
+    After(Timestamp),
+    /// `color:blue` or `color:#ff8800~40`
+    Color(ColorFilter),
+    /// `library:photos`
+    Library(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    And,
+    Or,
+    Not,
+    Term {
+        field: Option<String>,
+        value: String,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// Character the token starts at, counting from 1
+    position: usize,
+}
+
+/// Parse a search query, `None` if it has no terms
+///
+/// Errors are 400s naming the position of the problem.
+pub fn parse_query(query: &str) -> Result<Option<Expr>, AppError> {
+    let tokens = tokenize(query)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: query.chars().count() + 1,
+    };
+    let expr = parser.or()?;
+    if let Some(token) = parser.peek() {
+        return Err(syntax_error("unexpected ')'", token.position));
+    }
+
+    Ok(Some(expr))
+}
+
+fn syntax_error(message: &str, position: usize) -> AppError {
+    AppError::Text(
+        StatusCode::BAD_REQUEST,
+        format!("{} at position {}", message, position),
+    )
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, AppError> {
+    let chars = query.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut index = 0;
+
+    let is_word_end = |c: char| c.is_whitespace() || c == '(' || c == ')';
+
+    while index < chars.len() {
+        let c = chars[index];
+        let position = index + 1;
+
+        if c.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        let kind = match c {
+            '(' => {
+                index += 1;
+                TokenKind::Open
+            }
+            ')' => {
+                index += 1;
+                TokenKind::Close
+            }
+            // Negates a word or a group, `-` on its own is a word
+            '-' if chars
+                .get(index + 1)
+                .is_some_and(|&next| !next.is_whitespace() && next != ')') =>
+            {
+                index += 1;
+                TokenKind::Not
+            }
+            '"' => {
+                let (value, next) = read_quoted(&chars, index)?;
+                index = next;
+                TokenKind::Term {
+                    field: Some("tag".to_string()),
+                    value,
+                }
+            }
+            _ => {
+                let start = index;
+                while index < chars.len() && !is_word_end(chars[index]) && chars[index] != '"' {
+                    index += 1;
+                }
+                let word = chars[start..index].iter().collect::<String>();
+
+                match word.split_once(':') {
+                    Some((field, rest))
+                        if !field.is_empty() && field.chars().all(|c| c.is_ascii_alphabetic()) =>
+                    {
+                        let value = if rest.is_empty() && chars.get(index) == Some(&'"') {
+                            let (value, next) = read_quoted(&chars, index)?;
+                            index = next;
+                            value
+                        } else {
+                            rest.to_string()
+                        };
+                        if value.is_empty() {
+                            return Err(syntax_error(
+                                &format!("missing value for '{}:'", field),
+                                position,
+                            ));
+                        }
+                        TokenKind::Term {
+                            field: Some(field.to_lowercase()),
+                            value,
+                        }
+                    }
+                    _ => match word.as_str() {
+                        "AND" => TokenKind::And,
+                        "OR" => TokenKind::Or,
+                        "NOT" => TokenKind::Not,
+                        _ => TokenKind::Term {
+                            field: None,
+                            value: word,
+                        },
+                    },
+                }
+            }
+        };
+
+        tokens.push(Token { kind, position });
+    }
+
+    Ok(tokens)
+}
+
+/// Read the `"…"` starting at `start`, returning its content and the index after it
+fn read_quoted(chars: &[char], start: usize) -> Result<(String, usize), AppError> {
+    let length = chars[start + 1..]
+        .iter()
+        .position(|&c| c == '"')
+        .ok_or_else(|| syntax_error("unterminated quote", start + 1))?;
+
+    let value = chars[start + 1..start + 1 + length].iter().collect();
+    Ok((value, start + length + 2))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    /// Position reported for errors at the end of the query
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr, AppError> {
+        let mut terms = vec![self.and()?];
+        while self.peek().is_some_and(|token| token.kind == TokenKind::Or) {
+            self.next();
+            terms.push(self.and()?);
+        }
+
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::Or(terms)
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, AppError> {
+        let mut terms = vec![self.unary()?];
+        loop {
+            match self.peek().map(|token| &token.kind) {
+                None | Some(TokenKind::Or) | Some(TokenKind::Close) => break,
+                Some(TokenKind::And) => {
+                    self.next();
+                }
+                _ => {}
+            }
+            terms.push(self.unary()?);
+        }
+
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::And(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, AppError> {
+        let Some(token) = self.next() else {
+            return Err(syntax_error("expected a search term", self.end));
+        };
+
+        match token.kind {
+            TokenKind::Not => Ok(Expr::Not(Box::new(self.unary()?.exact()))),
+            TokenKind::Open => {
+                let expr = self.or()?;
+                match self.next() {
+                    Some(Token {
+                        kind: TokenKind::Close,
+                        ..
+                    }) => Ok(expr),
+                    _ => Err(syntax_error("missing ')'", token.position)),
+                }
+            }
+            TokenKind::Term { field, value } => {
+                Ok(Expr::Filter(filter(field, value, token.position)?))
+            }
+            TokenKind::Close => Err(syntax_error("unexpected ')'", token.position)),
+            TokenKind::And | TokenKind::Or => Err(syntax_error(
+                "expected a search term before the operator",
+                token.position,
+            )),
+        }
+    }
+}
+
+fn filter(field: Option<String>, value: String, position: usize) -> Result<Filter, AppError> {
+    let invalid = |expected: &str| {
+        syntax_error(
+            &format!("invalid value '{}', expected {}", value, expected),
+            position,
+        )
+    };
+
+    let Some(field) = field else {
+        return Ok(Filter::Word(value));
+    };
+
+    Ok(match field.as_str() {
+        "tag" => Filter::Tag(value),
+        "camera" => Filter::Camera(value),
+        "library" => Filter::Library(value),
+        "year" => Filter::Year(
+            value
+                .parse()
+                .ok()
+                .filter(|year| (1..=9999).contains(year))
+                .ok_or_else(|| invalid("a year like 2023"))?,
+        ),
+        "before" | "after" => {
+            let (start, end) = parse_period(&value)
+                .ok_or_else(|| invalid("a date like 2020, 2020-05 or 2020-05-17"))?;
+            if field == "before" {
+                Filter::Before(start)
+            } else {
+                Filter::After(end)
+            }
+        }
+        "color" => Filter::Color(parse_color_filter(&value).map_err(|error| match error {
+            AppError::Text(status, message) => {
+                AppError::Text(status, format!("{} at position {}", message, position))
+            }
+            error => error,
+        })?),
+        _ => {
+            return Err(syntax_error(
+                &format!("unknown field '{}'", field),
+                position,
+            ))
+        }
+    })
+}
+
+/// Start and end in UTC of the year, month or day written as `2020`, `2020-05` or `2020-05-17`
+pub fn parse_period(value: &str) -> Option<(Timestamp, Timestamp)> {
+    let parts = value
+        .split('-')
+        .map(|part| part.parse::<i16>().ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    let (start, end) = match parts.as_slice() {
+        [year] => {
+            let start = Date::new(*year, 1, 1).ok()?;
+            (start, start.checked_add(1.year()).ok()?)
+        }
+        [year, month] => {
+            let start = Date::new(*year, i8::try_from(*month).ok()?, 1).ok()?;
+            (start, start.checked_add(1.month()).ok()?)
+        }
+        [year, month, day] => {
+            let start =
+                Date::new(*year, i8::try_from(*month).ok()?, i8::try_from(*day).ok()?).ok()?;
+            (start, start.checked_add(1.day()).ok()?)
+        }
+        _ => return None,
+    };
+
+    let timestamp = |date: Date| Some(date.to_zoned(TimeZone::UTC).ok()?.timestamp());
+    Some((timestamp(start)?, timestamp(end)?))
+}
+
+impl Expr {
+    /// Turn bare words into exact tag matches, excluding near misses would exclude too much
+    fn exact(self) -> Expr {
+        match self {
+            Expr::And(terms) => Expr::And(terms.into_iter().map(Expr::exact).collect()),
+            Expr::Or(terms) => Expr::Or(terms.into_iter().map(Expr::exact).collect()),
+            Expr::Not(term) => Expr::Not(term),
+            Expr::Filter(Filter::Word(word)) => Expr::Filter(Filter::Tag(word)),
+            Expr::Filter(filter) => Expr::Filter(filter),
+        }
+    }
+
+    /// Append the condition to a query selecting from `image`
+    pub fn push_sql(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Expr::And(terms) | Expr::Or(terms) => {
+                let operator = if matches!(self, Expr::And(_)) {
+                    " AND "
+                } else {
+                    " OR "
+                };
+                builder.push("(");
+                for (index, term) in terms.iter().enumerate() {
+                    if index > 0 {
+                        builder.push(operator);
+                    }
+                    term.push_sql(builder);
+                }
+                builder.push(")");
+            }
+            Expr::Not(term) => {
+                builder.push("NOT ");
+                term.push_sql(builder);
+            }
+            Expr::Filter(filter) => filter.push_sql(builder),
+        }
+    }
+
+    /// Append how well the image's tags match the query, so images matching more alternatives
+    /// of an `OR` or matching more closely rank first
+    pub fn push_relevance(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        let mut filters = vec![];
+        self.collect_tag_filters(&mut filters);
+
+        if filters.is_empty() {
+            builder.push("0::float8");
+            return;
+        }
+        builder.push("(");
+        for (index, filter) in filters.into_iter().enumerate() {
+            if index > 0 {
+                builder.push(" + ");
+            }
+            match filter {
+                Filter::Word(word) => {
+                    let word = word.to_lowercase();
+                    builder
+                        .push(
+                            "COALESCE((
+                                SELECT MAX(CASE WHEN ancestor.id IN (SELECT tag_id FROM tag_alias WHERE alias = ",
+                        )
+                        .push_bind(word.clone())
+                        .push(") THEN 1 ELSE SIMILARITY(ancestor.description, ")
+                        .push_bind(word.clone())
+                        .push(
+                            ") END)
+                                FROM image_tag
+                                JOIN tag descendant ON descendant.id = image_tag.tag_id
+                                JOIN tag ancestor ON ",
+                        )
+                        .push(DESCENDANT_OF_ANCESTOR)
+                        .push(" WHERE image_tag.image_id = image.id AND ancestor.id IN (");
+                    push_similar_tags(builder, &word);
+                    builder.push(")), 0)");
+                }
+                filter => {
+                    builder.push("(");
+                    filter.push_sql(builder);
+                    builder.push(")::integer");
+                }
+            }
+        }
+        builder.push(")::float8");
+    }
+
+    /// Tag filters that count towards relevance, leaving out negated ones
+    fn collect_tag_filters<'e>(&'e self, filters: &mut Vec<&'e Filter>) {
+        match self {
+            Expr::And(terms) | Expr::Or(terms) => terms
+                .iter()
+                .for_each(|term| term.collect_tag_filters(filters)),
+            Expr::Not(_) => {}
+            Expr::Filter(filter @ (Filter::Word(_) | Filter::Tag(_))) => filters.push(filter),
+            Expr::Filter(_) => {}
+        }
+    }
+}
+
+/// Joins a tag to itself and its descendants, which a search for it also matches
+const DESCENDANT_OF_ANCESTOR: &str =
+    "(descendant.path = ancestor.path OR STARTS_WITH(descendant.path, ancestor.path || '/'))";
+
+/// Append a query for the ids of the tags matching `word`, which must be lowercase
+///
+/// Tags are stored lowercase, so the trigram and prefix indexes on `description` apply.
+fn push_similar_tags(builder: &mut QueryBuilder<'_, Postgres>, word: &str) {
+    builder
+        .push("SELECT tag.id FROM tag WHERE tag.description = ")
+        .push_bind(word.to_string())
+        .push(" OR tag.path = ")
+        .push_bind(word.to_string())
+        .push(" OR tag.id IN (SELECT tag_id FROM tag_alias WHERE alias = ")
+        .push_bind(word.to_string())
+        .push(") OR tag.description % ")
+        .push_bind(word.to_string());
+    if word.chars().count() >= MIN_PREFIX_LENGTH {
+        builder
+            .push(" OR tag.description LIKE ")
+            .push_bind(format!("{}%", escape_like(word)));
+    }
+}
+
+/// Append a condition for images with one of the tags `push_tags` selects, or a descendant
+fn push_has_descendant(
+    builder: &mut QueryBuilder<'_, Postgres>,
+    push_tags: impl FnOnce(&mut QueryBuilder<'_, Postgres>),
+) {
+    builder
+        .push(
+            "EXISTS (
+                SELECT 1 FROM image_tag
+                JOIN tag descendant ON descendant.id = image_tag.tag_id
+                JOIN tag ancestor ON ",
+        )
+        .push(DESCENDANT_OF_ANCESTOR)
+        .push(" WHERE image_tag.image_id = image.id AND ancestor.id IN (");
+    push_tags(builder);
+    builder.push("))");
+}
+
+impl Filter {
+    fn push_sql(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        match self {
+            Filter::Word(word) => {
+                push_has_descendant(builder, |builder| {
+                    push_similar_tags(builder, &word.to_lowercase())
+                });
+            }
+            Filter::Tag(tag) => {
+                // A name anywhere in the hierarchy, a full path like `places/france`, an alias or
+                // a path ending in the alias of a child, like `travel/nyc`
+                let tag = normalize_tag_path(tag);
+                push_has_descendant(builder, |builder| {
+                    builder
+                        .push("SELECT tag.id FROM tag WHERE tag.description = ")
+                        .push_bind(tag.clone())
+                        .push(" OR tag.path = ")
+                        .push_bind(tag.clone())
+                        .push(" OR tag.id IN (SELECT tag_id FROM tag_alias WHERE alias = ")
+                        .push_bind(tag.clone())
+                        .push(
+                            ") OR tag.id IN (
+                                SELECT tag_alias.tag_id FROM tag_alias
+                                JOIN tag aliased ON aliased.id = tag_alias.tag_id
+                                JOIN tag parent ON parent.id = aliased.parent_id
+                                WHERE parent.path || '/' || tag_alias.alias = ",
+                        )
+                        .push_bind(tag)
+                        .push(")");
+                });
+            }
+            Filter::Camera(camera) => {
+                // EXIF for photos, the QuickTime tag for videos from phones
+                builder
+                    .push(
+                        "COALESCE(CONCAT_WS(' ',
+                            image.metadata::jsonb ->> 'Make',
+                            image.metadata::jsonb ->> 'Model',
+                            image.metadata::jsonb ->> 'com.apple.quicktime.model'
+                        ) ILIKE ",
+                    )
+                    .push_bind(format!("%{}%", escape_like(camera)))
+                    .push(", FALSE)");
+            }
+            Filter::Year(year) => {
+                // A range rather than EXTRACT, so the index on `taken_at` applies
+                builder
+                    .push("(image.taken_at >= ")
+                    .push_bind(format!("{:04}-01-01T00:00:00Z", year))
+                    .push("::text::timestamptz AND image.taken_at < ")
+                    .push_bind(format!("{:04}-01-01T00:00:00Z", year + 1))
+                    .push("::text::timestamptz)");
+            }
+            Filter::Before(timestamp) => {
+                builder
+                    .push("image.taken_at < ")
+                    .push_bind(timestamp.to_string())
+                    .push("::text::timestamptz");
+            }
+            Filter::After(timestamp) => {
+                builder
+                    .push("image.taken_at >= ")
+                    .push_bind(timestamp.to_string())
+                    .push("::text::timestamptz");
+            }
+            Filter::Color(color) => {
+                builder
+                    .push(
+                        "EXISTS (
+                            SELECT 1 FROM UNNEST(image.palette) dominant(color), UNNEST(",
+                    )
+                    .push_bind(color.shades.clone())
+                    .push(
+                        "::integer[]) wanted(color)
+                            WHERE color_distance(dominant.color, wanted.color) <= ",
+                    )
+                    .push_bind(color.tolerance)
+                    .push(")");
+            }
+            Filter::Library(library) => {
+                builder
+                    .push("image.library IS NOT DISTINCT FROM ")
+                    .push_bind(library.clone());
+            }
+        }
+    }
+}
+
+pub fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render the parsed query with explicit parentheses, bare words as they are
+    fn parse(query: &str) -> String {
+        fn render(expr: &Expr) -> String {
+            match expr {
+                Expr::And(terms) | Expr::Or(terms) => {
+                    let operator = if matches!(expr, Expr::And(_)) {
+                        " AND "
+                    } else {
+                        " OR "
+                    };
+                    let terms = terms.iter().map(render).collect::<Vec<_>>();
+                    format!("({})", terms.join(operator))
+                }
+                Expr::Not(term) => format!("NOT {}", render(term)),
+                Expr::Filter(Filter::Word(word)) => word.clone(),
+                Expr::Filter(Filter::Tag(tag)) => format!("tag:{}", tag),
+                Expr::Filter(filter) => format!("{:?}", filter),
+            }
+        }
+
+        render(&parse_query(query).unwrap().unwrap())
+    }
+
+    fn parse_error(query: &str) -> String {
+        match parse_query(query) {
+            Err(AppError::Text(StatusCode::BAD_REQUEST, message)) => message,
+            result => panic!("expected a syntax error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn and_binds_stronger_than_or() {
+        assert_eq!(parse("a b OR c"), "((a AND b) OR c)");
+        assert_eq!(parse("a OR b c"), "(a OR (b AND c))");
+        assert_eq!(parse("a AND b OR c AND d"), "((a AND b) OR (c AND d))");
+    }
+
+    #[test]
+    fn not_binds_to_the_next_term() {
+        assert_eq!(parse("-a b"), "(NOT tag:a AND b)");
+        assert_eq!(parse("NOT a OR b"), "(NOT tag:a OR b)");
+        assert_eq!(parse("-(a OR b) c"), "(NOT (tag:a OR tag:b) AND c)");
+    }
+
+    #[test]
+    fn negated_words_match_exactly() {
+        assert_eq!(parse("-cat"), "NOT tag:cat");
+        assert_eq!(parse("NOT (cat -dog)"), "NOT (tag:cat AND NOT tag:dog)");
+    }
+
+    #[test]
+    fn parentheses_group_terms() {
+        assert_eq!(parse("(a OR b) c"), "((a OR b) AND c)");
+        assert_eq!(parse("a (b OR (c d))"), "(a AND (b OR (c AND d)))");
+    }
+
+    #[test]
+    fn quotes_match_tags() {
+        assert_eq!(parse("\"new york\" cats"), "(tag:new york AND cats)");
+        assert_eq!(parse("tag:\"new york\""), "tag:new york");
+        assert_eq!(parse("x-ray"), "x-ray");
+    }
+
+    #[test]
+    fn empty_query_has_no_terms() {
+        assert!(parse_query("  ").unwrap().is_none());
+    }
+
+    #[test]
+    fn unterminated_quote() {
+        assert_eq!(
+            parse_error("cats \"new york"),
+            "unterminated quote at position 6"
+        );
+        assert_eq!(
+            parse_error("tag:\"new york"),
+            "unterminated quote at position 5"
+        );
+    }
+
+    #[test]
+    fn unknown_field() {
+        assert_eq!(
+            parse_error("cats lens:35mm"),
+            "unknown field 'lens' at position 6"
+        );
+    }
+
+    #[test]
+    fn invalid_values_and_structure() {
+        assert_eq!(
+            parse_error("year:abc"),
+            "invalid value 'abc', expected a year like 2023 at position 1"
+        );
+        assert_eq!(
+            parse_error("tag:"),
+            "missing value for 'tag:' at position 1"
+        );
+        assert_eq!(parse_error("(a OR b"), "missing ')' at position 1");
+        assert_eq!(parse_error("a b)"), "unexpected ')' at position 4");
+        assert_eq!(parse_error("a OR"), "expected a search term at position 5");
+        assert_eq!(
+            parse_error("OR a"),
+            "expected a search term before the operator at position 1"
+        );
+    }
+}
@@ -0,0 +1,436 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::Cursor,
+    path::{self, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{extract::State, http::StatusCode};
+use quick_xml::{
+    events::{BytesEnd, BytesStart, BytesText, Event},
+    Reader, Writer,
+};
+use sqlx::{query, query_as, FromRow, Pool, Postgres};
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    time::sleep,
+};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    auth::AdminAccount,
+    error::AppError,
+    library::LibraryRoot,
+    xmp::{sidecar_modified_at, sidecar_path},
+    AppState,
+};
+
+/// How long to wait for more tag changes before writing a batch of sidecars
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+const BATCH_SIZE: usize = 500;
+
+const EMPTY_SIDECAR: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about="">
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#;
+const DC_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+const EXIF_NAMESPACE: &str = "http://ns.adobe.com/exif/1.0/";
+const LIGHTROOM_NAMESPACE: &str = "http://ns.adobe.com/lightroom/1.0/";
+const KEYWORD_PROPERTIES: &[&[u8]] = &[b"dc:subject", b"lr:hierarchicalSubject"];
+const GPS_PROPERTIES: &[&[u8]] = &[b"exif:GPSLatitude", b"exif:GPSLongitude"];
+
+/// Queues images whose tags changed so their XMP sidecars are rewritten in the background
+///
+/// Only started if `XMP_WRITEBACK` is set to `true`. Sidecars in read-only library roots are
+/// left alone and originals are never touched.
+#[derive(Clone, Debug)]
+pub struct SidecarWriter {
+    sender: UnboundedSender<Uuid>,
+}
+
+impl SidecarWriter {
+    pub fn start(pool: Pool<Postgres>, libraries: Arc<Vec<LibraryRoot>>) -> Option<Self> {
+        if std::env::var("XMP_WRITEBACK").ok().as_deref() != Some("true") {
+            return None;
+        }
+
+        let (sender, receiver) = unbounded_channel();
+        tokio::spawn(write_sidecars(pool, libraries, receiver));
+
+        Some(SidecarWriter { sender })
+    }
+
+    pub fn queue(&self, image_ids: &[Uuid]) {
+        for image_id in image_ids {
+            if self.sender.send(*image_id).is_err() {
+                error!(message = "sidecar writer is not running");
+                return;
+            }
+        }
+    }
+}
+
+async fn write_sidecars(
+    pool: Pool<Postgres>,
+    libraries: Arc<Vec<LibraryRoot>>,
+    mut receiver: UnboundedReceiver<Uuid>,
+) {
+    while let Some(image_id) = receiver.recv().await {
+        let mut batch = HashSet::from([image_id]);
+
+        // Collect whatever else arrives shortly after, so bulk edits are written in one go
+        let deadline = sleep(BATCH_DELAY);
+        tokio::pin!(deadline);
+        while batch.len() < BATCH_SIZE {
+            tokio::select! {
+                _ = &mut deadline => break,
+                image_id = receiver.recv() => match image_id {
+                    Some(image_id) => {
+                        batch.insert(image_id);
+                    }
+                    None => break,
+                },
+            }
+        }
+
+        let batch = batch.into_iter().collect::<Vec<_>>();
+        if let Err(error) = write_batch(&pool, &libraries, &batch).await {
+            error!(message = "writing sidecars failed", images = batch.len(), %error);
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(images = image_ids.len()))]
+async fn write_batch(
+    pool: &Pool<Postgres>,
+    libraries: &[LibraryRoot],
+    image_ids: &[Uuid],
+) -> Result<(), AppError> {
+    #[derive(FromRow)]
+    struct Image {
+        id: Uuid,
+        filename: String,
+        tags: Option<Vec<String>>,
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+    }
+
+    let images = query_as!(
+        Image,
+        "
+            SELECT image.id, image.filename, ARRAY_REMOVE(ARRAY_AGG(tag.path ORDER BY tag.path), NULL) tags,
+                image.latitude, image.longitude
+            FROM image
+            LEFT JOIN image_tag ON image.id = image_tag.image_id
+            LEFT JOIN tag ON image_tag.tag_id = tag.id
+            WHERE image.id = ANY($1)
+            GROUP BY image.id;
+        ",
+        image_ids
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut written = 0;
+    for image in images {
+        let file = path::Path::new(&image.filename);
+
+        let writable = libraries
+            .iter()
+            .find(|library| file.starts_with(&library.path))
+            .is_some_and(|library| !library.read_only);
+        if !writable {
+            continue;
+        }
+
+        let sidecar = writable_sidecar_path(file);
+
+        let tags = image.tags.unwrap_or_default();
+        let location = image.latitude.zip(image.longitude);
+        match write_sidecar(&sidecar, &tags, location) {
+            Ok(()) => written += 1,
+            Err(error) => {
+                warn!(message = "writing sidecar failed", sidecar = ?sidecar, %error);
+                continue;
+            }
+        }
+
+        // Don't import our own write as an external edit on the next scan, and remember what
+        // was written so keywords removed from it later are told apart
+        let modified_at = match sidecar_modified_at(&sidecar) {
+            Ok(modified_at) => modified_at,
+            Err(error) => {
+                warn!(message = "reading sidecar modification time failed", sidecar = ?sidecar, %error);
+                continue;
+            }
+        };
+        query!(
+            "UPDATE image SET sidecar_modified_at = $2, sidecar_keywords = $3 WHERE id = $1;",
+            image.id,
+            modified_at,
+            &tags
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    info!(message = "wrote sidecars", written);
+
+    Ok(())
+}
+
+/// Where to write the sidecar of `file`, `IMG_0001.NEF.xmp` unless `IMG_0001.xmp` exists and
+/// belongs to it alone
+///
+/// A RAW+JPEG pair would otherwise share `IMG_0001.xmp` and overwrite each other's tags.
+fn writable_sidecar_path(file: &path::Path) -> PathBuf {
+    match sidecar_path(file) {
+        Some(own) if own.file_stem() == file.file_name() => own,
+        Some(shared) if !has_siblings(file) => shared,
+        _ => {
+            let mut appended = file.as_os_str().to_owned();
+            appended.push(".xmp");
+            PathBuf::from(appended)
+        }
+    }
+}
+
+/// Whether other files in the folder have the same stem as `file`, besides sidecars
+fn has_siblings(file: &path::Path) -> bool {
+    let (Some(folder), Some(stem)) = (file.parent(), file.file_stem()) else {
+        return false;
+    };
+    let Ok(entries) = fs::read_dir(folder) else {
+        return false;
+    };
+
+    entries.filter_map(|entry| entry.ok()).any(|entry| {
+        let sibling = entry.path();
+        sibling != file
+            && sibling.file_stem() == Some(stem)
+            && !sibling
+                .extension()
+                .is_some_and(|extension| extension.eq_ignore_ascii_case("xmp"))
+    })
+}
+
+fn write_sidecar(
+    sidecar: &path::Path,
+    tags: &[String],
+    location: Option<(f64, f64)>,
+) -> Result<(), AppError> {
+    let existing = if sidecar.is_file() {
+        fs::read_to_string(sidecar)?
+    } else {
+        EMPTY_SIDECAR.to_string()
+    };
+
+    let content = update_xmp(&existing, tags, location)?;
+
+    // Write next to the sidecar first so a crash never leaves a truncated file behind
+    let mut temporary = sidecar.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, content)?;
+    fs::rename(&temporary, sidecar)?;
+
+    Ok(())
+}
+
+/// Replace the dc:subject and lr:hierarchicalSubject bags of an XMP packet with `tags` and, if
+/// the image has a location, its GPS coordinates, keeping everything else as is
+fn update_xmp(
+    xmp: &str,
+    tags: &[String],
+    location: Option<(f64, f64)>,
+) -> Result<Vec<u8>, AppError> {
+    let mut reader = Reader::from_str(xmp);
+    let mut writer = Writer::new(Cursor::new(Vec::new()));
+
+    let replaced = |name: &[u8]| {
+        KEYWORD_PROPERTIES.contains(&name) || (location.is_some() && GPS_PROPERTIES.contains(&name))
+    };
+    let mut written = false;
+    // Depth inside a replaced element being dropped
+    let mut skipping = 0;
+
+    loop {
+        let event = reader.read_event()?;
+
+        if skipping > 0 {
+            match event {
+                Event::Start(_) => skipping += 1,
+                Event::End(_) => skipping -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        match event {
+            Event::Start(element) if replaced(element.name().as_ref()) => {
+                if !written {
+                    write_properties(&mut writer, tags, location)?;
+                    written = true;
+                }
+                skipping = 1;
+            }
+            Event::Empty(element) if replaced(element.name().as_ref()) => {
+                if !written {
+                    write_properties(&mut writer, tags, location)?;
+                    written = true;
+                }
+            }
+            Event::Start(element) if element.name().as_ref() == b"rdf:Description" => {
+                writer.write_event(Event::Start(prepare_description(element, location)))?;
+            }
+            Event::Empty(element) if element.name().as_ref() == b"rdf:Description" && !written => {
+                let element = prepare_description(element, location);
+                let end = element.to_end().into_owned();
+                writer.write_event(Event::Start(element))?;
+                write_properties(&mut writer, tags, location)?;
+                writer.write_event(Event::End(end))?;
+                written = true;
+            }
+            Event::End(element) if element.name().as_ref() == b"rdf:Description" && !written => {
+                write_properties(&mut writer, tags, location)?;
+                writer.write_event(Event::End(element))?;
+                written = true;
+            }
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok(writer.into_inner().into_inner())
+}
+
+/// Declare the namespaces written into rdf:Description and drop GPS attributes being replaced
+fn prepare_description(element: BytesStart, location: Option<(f64, f64)>) -> BytesStart<'static> {
+    let mut declared_dc = false;
+    let mut declared_lr = false;
+    let mut declared_exif = false;
+
+    let mut prepared =
+        BytesStart::new(String::from_utf8_lossy(element.name().as_ref()).to_string());
+    for attribute in element.attributes().flatten() {
+        let key = attribute.key.as_ref();
+        declared_dc |= key == b"xmlns:dc";
+        declared_lr |= key == b"xmlns:lr";
+        declared_exif |= key == b"xmlns:exif";
+        if location.is_some() && GPS_PROPERTIES.contains(&key) {
+            continue;
+        }
+        prepared.push_attribute(attribute);
+    }
+
+    if !declared_dc {
+        prepared.push_attribute(("xmlns:dc", DC_NAMESPACE));
+    }
+    if !declared_lr {
+        prepared.push_attribute(("xmlns:lr", LIGHTROOM_NAMESPACE));
+    }
+    if location.is_some() && !declared_exif {
+        prepared.push_attribute(("xmlns:exif", EXIF_NAMESPACE));
+    }
+    prepared
+}
+
+fn write_properties(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    tags: &[String],
+    location: Option<(f64, f64)>,
+) -> Result<(), AppError> {
+    // Like Lightroom, every level of a nested tag is also a flat keyword
+    let mut subjects = tags
+        .iter()
+        .flat_map(|tag| tag.split('/'))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    subjects.sort();
+    subjects.dedup();
+    write_bag(writer, "dc:subject", &subjects)?;
+
+    let hierarchical = tags
+        .iter()
+        .filter(|tag| tag.contains('/'))
+        .map(|tag| tag.replace('/', "|"))
+        .collect::<Vec<_>>();
+    if !hierarchical.is_empty() {
+        write_bag(writer, "lr:hierarchicalSubject", &hierarchical)?;
+    }
+
+    if let Some((latitude, longitude)) = location {
+        for (name, value) in [
+            ("exif:GPSLatitude", xmp_coordinate(latitude, 'N', 'S')),
+            ("exif:GPSLongitude", xmp_coordinate(longitude, 'E', 'W')),
+        ] {
+            writer.write_event(Event::Start(BytesStart::new(name)))?;
+            writer.write_event(Event::Text(BytesText::new(&value)))?;
+            writer.write_event(Event::End(BytesEnd::new(name)))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_bag(
+    writer: &mut Writer<Cursor<Vec<u8>>>,
+    name: &str,
+    items: &[String],
+) -> Result<(), AppError> {
+    writer.write_event(Event::Start(BytesStart::new(name)))?;
+    writer.write_event(Event::Start(BytesStart::new("rdf:Bag")))?;
+    for item in items {
+        writer.write_event(Event::Start(BytesStart::new("rdf:li")))?;
+        writer.write_event(Event::Text(BytesText::new(item)))?;
+        writer.write_event(Event::End(BytesEnd::new("rdf:li")))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("rdf:Bag")))?;
+    writer.write_event(Event::End(BytesEnd::new(name)))?;
+
+    Ok(())
+}
+
+/// XMP writes coordinates as degrees and decimal minutes, e.g. `47,22.6140N`
+fn xmp_coordinate(value: f64, positive: char, negative: char) -> String {
+    let direction = if value < 0.0 { negative } else { positive };
+    let value = value.abs();
+    let degrees = value.trunc();
+    let minutes = (value - degrees) * 60.0;
+
+    format!("{},{:.4}{}", degrees as u32, minutes, direction)
+}
+
+/// Queue the sidecars of all images for rewriting, for accounts in `ADMIN_USERS` only
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+))]
+pub async fn rewrite_all_sidecars(
+    account: AdminAccount,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AppError> {
+    info!(message = "Rewriting all sidecars");
+
+    let Some(sidecars) = &state.sidecars else {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            "XMP write-back is not enabled".to_string(),
+        ));
+    };
+
+    let images = query!("SELECT id FROM image;")
+        .fetch_all(&state.pool)
+        .await?;
+
+    sidecars.queue(&images.iter().map(|image| image.id).collect::<Vec<_>>());
+
+    Ok(StatusCode::ACCEPTED)
+}
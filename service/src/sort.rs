@@ -0,0 +1,178 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, QueryBuilder};
+use uuid::Uuid;
+
+use crate::{error::AppError, query::Expr};
+
+/// Order of search results
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    /// Capture time, newest first
+    #[default]
+    Newest,
+    /// Capture time, oldest first
+    Oldest,
+    /// Most recently indexed first
+    Added,
+    /// File name, A to Z
+    Filename,
+    /// Highest rated first, unrated last
+    Rating,
+    /// Shuffled by a seed, so the order stays the same across pages
+    Random,
+    /// Images whose tags match the query best first
+    Relevance,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SortKey {
+    CapturedAt,
+    FileName,
+    Rating,
+    Random,
+    Relevance,
+}
+
+impl SortKey {
+    /// Bind a value read from a cursor with the key's type, so it compares the same way
+    fn push_value(
+        self,
+        builder: &mut QueryBuilder<'_, Postgres>,
+        value: &str,
+    ) -> Result<(), AppError> {
+        let invalid = || AppError::Text(StatusCode::BAD_REQUEST, "invalid cursor".to_string());
+        match self {
+            SortKey::Rating => {
+                builder.push_bind(value.parse::<i32>().map_err(|_| invalid())?);
+            }
+            SortKey::Relevance => {
+                builder.push_bind(value.parse::<f64>().map_err(|_| invalid())?);
+            }
+            SortKey::CapturedAt | SortKey::FileName | SortKey::Random => {
+                builder.push_bind(value.to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How a search is ordered, followed by `image.id` to break ties
+pub struct Sort<'q> {
+    order: SortOrder,
+    filter: Option<&'q Expr>,
+    seed: String,
+}
+
+impl<'q> Sort<'q> {
+    pub fn new(order: SortOrder, filter: Option<&'q Expr>, seed: String) -> Self {
+        Sort {
+            order,
+            filter,
+            seed,
+        }
+    }
+
+    fn keys(&self) -> &'static [SortKey] {
+        match self.order {
+            SortOrder::Newest | SortOrder::Oldest => &[SortKey::CapturedAt],
+            // Image ids are UUIDv7, so they grow with the time they were added
+            SortOrder::Added => &[],
+            SortOrder::Filename => &[SortKey::FileName],
+            SortOrder::Rating => &[SortKey::Rating, SortKey::CapturedAt],
+            SortOrder::Random => &[SortKey::Random],
+            SortOrder::Relevance => &[SortKey::Relevance, SortKey::CapturedAt],
+        }
+    }
+
+    fn descending(&self) -> bool {
+        matches!(
+            self.order,
+            SortOrder::Newest | SortOrder::Added | SortOrder::Rating | SortOrder::Relevance
+        )
+    }
+
+    fn push_key(&self, builder: &mut QueryBuilder<'_, Postgres>, key: SortKey) {
+        match key {
+            SortKey::CapturedAt => {
+                builder.push("image.captured_at");
+            }
+            SortKey::FileName => {
+                builder.push("REGEXP_REPLACE(image.filename, '^.*/', '')");
+            }
+            SortKey::Rating => {
+                builder.push("COALESCE(image.rating, 0)");
+            }
+            SortKey::Random => {
+                builder
+                    .push("MD5(image.id::text || ")
+                    .push_bind(self.seed.clone())
+                    .push(")");
+            }
+            SortKey::Relevance => match self.filter {
+                Some(filter) => filter.push_relevance(builder),
+                None => {
+                    builder.push("0::float8");
+                }
+            },
+        }
+    }
+
+    /// Append the sort keys as a `sort_keys` text array column, to build the next cursor from
+    pub fn push_select(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        builder.push("ARRAY[");
+        for (index, key) in self.keys().iter().enumerate() {
+            if index > 0 {
+                builder.push(", ");
+            }
+            self.push_key(builder, *key);
+            builder.push("::text");
+        }
+        builder.push("]::text[] sort_keys");
+    }
+
+    /// Append the condition for images after the position of a cursor
+    ///
+    /// The cursor has to come from a search with the same order, its keys mean nothing in
+    /// another one.
+    pub fn push_after(
+        &self,
+        builder: &mut QueryBuilder<'_, Postgres>,
+        order: SortOrder,
+        values: &[String],
+        id: Uuid,
+    ) -> Result<(), AppError> {
+        let invalid = || AppError::Text(StatusCode::BAD_REQUEST, "invalid cursor".to_string());
+        if order != self.order || values.len() != self.keys().len() {
+            return Err(invalid());
+        }
+
+        builder.push(" AND (");
+        for key in self.keys() {
+            self.push_key(builder, *key);
+            builder.push(", ");
+        }
+        builder.push("image.id) ");
+        builder.push(if self.descending() { "<" } else { ">" });
+        builder.push(" (");
+        for (key, value) in self.keys().iter().zip(values) {
+            key.push_value(builder, value)?;
+            builder.push(", ");
+        }
+        builder.push_bind(id).push(")");
+
+        Ok(())
+    }
+
+    pub fn push_order_by(&self, builder: &mut QueryBuilder<'_, Postgres>) {
+        let direction = if self.descending() { " DESC" } else { " ASC" };
+
+        builder.push(" ORDER BY ");
+        for key in self.keys() {
+            self.push_key(builder, *key);
+            builder.push(direction).push(", ");
+        }
+        builder.push("image.id").push(direction);
+    }
+}
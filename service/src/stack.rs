@@ -0,0 +1,374 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{self, PathBuf},
+};
+
+use crate::{
+    auth::AuthenticatedAccount,
+    decode::{extension_format, SourceFormat},
+    error::AppError,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use jiff::Timestamp;
+use serde::Serialize;
+use sqlx::{query, query_as, FromRow, Postgres, Transaction};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::AppState;
+
+/// Cameras shoot bursts at several frames a second, shots further apart are taken one by one
+const DEFAULT_BURST_WINDOW_MS: i64 = 500;
+
+#[derive(FromRow)]
+struct StackCandidate {
+    id: Uuid,
+    filename: String,
+    captured_at: String,
+    stack_id: Option<Uuid>,
+    media_kind: String,
+}
+
+/// Group indexed images into stacks
+///
+/// Files in the same folder sharing a file stem (RAW+JPEG pairs, Live Photo HEIC+MOV) are
+/// always stacked. Consecutive shots in a folder captured within `STACK_BURST_MS` milliseconds
+/// of each other, 500 by default and 0 to turn it off, are stacked as a burst.
+#[tracing::instrument(skip_all)]
+pub async fn stack_images(state: &AppState) -> Result<(), AppError> {
+    let images = query_as!(
+        StackCandidate,
+        "
+            SELECT id, filename, captured_at, stack_id, media_kind
+            FROM image
+            ORDER BY captured_at;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    #[derive(FromRow)]
+    struct Stack {
+        id: Uuid,
+        representative_id: Option<Uuid>,
+    }
+
+    let representatives = query_as!(Stack, "SELECT id, representative_id FROM stack;")
+        .fetch_all(&state.pool)
+        .await?
+        .into_iter()
+        .map(|stack| (stack.id, stack.representative_id))
+        .collect::<HashMap<_, _>>();
+
+    // E.g. `/image_dir/DSC_0001.NEF` and `/image_dir/DSC_0001.JPG`
+    let mut pairs: HashMap<(PathBuf, String), Vec<&StackCandidate>> = HashMap::new();
+    for image in &images {
+        let file = path::Path::new(&image.filename);
+        let (Some(folder), Some(stem)) = (file.parent(), file.file_stem()) else {
+            continue;
+        };
+        pairs
+            .entry((folder.to_path_buf(), stem.to_string_lossy().to_lowercase()))
+            .or_default()
+            .push(image);
+    }
+
+    let mut paired = HashSet::new();
+    for members in pairs.values().filter(|members| members.len() > 1) {
+        let Some(representative) = members.iter().min_by_key(|m| pair_rank(m)) else {
+            continue;
+        };
+        let kind = if members.iter().any(|m| m.media_kind == "video") {
+            "live"
+        } else {
+            "pair"
+        };
+
+        save_stack(state, &representatives, kind, members, representative.id).await?;
+        paired.extend(members.iter().map(|m| m.id));
+    }
+
+    let burst_window = match std::env::var("STACK_BURST_MS") {
+        Ok(burst_window) => match burst_window.parse::<i64>() {
+            Ok(burst_window) => burst_window,
+            Err(_) => {
+                warn!(message = "STACK_BURST_MS is not a number of milliseconds", %burst_window);
+                DEFAULT_BURST_WINDOW_MS
+            }
+        },
+        Err(_) => DEFAULT_BURST_WINDOW_MS,
+    };
+    if burst_window <= 0 {
+        return Ok(());
+    }
+
+    // Images are sorted by capture time already, so each folder's list is too
+    let mut folders: HashMap<PathBuf, Vec<(&StackCandidate, Timestamp)>> = HashMap::new();
+    for image in images.iter().filter(|image| !paired.contains(&image.id)) {
+        let Some(folder) = path::Path::new(&image.filename).parent() else {
+            continue;
+        };
+        let Ok(captured_at) = image.captured_at.parse::<Timestamp>() else {
+            continue;
+        };
+        folders
+            .entry(folder.to_path_buf())
+            .or_default()
+            .push((image, captured_at));
+    }
+
+    for shots in folders.values() {
+        let mut burst: Vec<&StackCandidate> = vec![];
+        let mut previous: Option<Timestamp> = None;
+
+        for (image, captured_at) in shots {
+            let continues_burst = previous.is_some_and(|previous| {
+                captured_at.as_millisecond() - previous.as_millisecond() <= burst_window
+            });
+
+            if !continues_burst {
+                if burst.len() > 1 {
+                    save_stack(state, &representatives, "burst", &burst, burst[0].id).await?;
+                }
+                burst.clear();
+            }
+
+            burst.push(image);
+            previous = Some(*captured_at);
+        }
+
+        if burst.len() > 1 {
+            save_stack(state, &representatives, "burst", &burst, burst[0].id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Preference for the image representing a pair, lower is better
+fn pair_rank(image: &StackCandidate) -> u8 {
+    if image.media_kind == "video" {
+        return 3;
+    }
+
+    // The extension is enough once `media_kind` has told videos apart
+    match extension_format(path::Path::new(&image.filename)) {
+        SourceFormat::Standard => 0,
+        SourceFormat::Heif => 1,
+        SourceFormat::Raw => 2,
+        SourceFormat::Video => 3,
+    }
+}
+
+async fn save_stack(
+    state: &AppState,
+    representatives: &HashMap<Uuid, Option<Uuid>>,
+    kind: &str,
+    members: &[&StackCandidate],
+    representative_id: Uuid,
+) -> Result<(), AppError> {
+    let existing = members.iter().find_map(|m| m.stack_id);
+
+    if let Some(stack_id) = existing {
+        let unchanged = members.iter().all(|m| m.stack_id == Some(stack_id))
+            && representatives.get(&stack_id) == Some(&Some(representative_id));
+        if unchanged {
+            return Ok(());
+        }
+    }
+
+    let mut tx = state.pool.begin().await?;
+
+    let stack_id = match existing {
+        Some(stack_id) => {
+            query!(
+                "UPDATE stack SET representative_id = $2 WHERE id = $1;",
+                stack_id,
+                representative_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            stack_id
+        }
+        None => {
+            let stack_id = Uuid::now_v7();
+            query!(
+                "INSERT INTO stack (id, kind, representative_id) VALUES ($1, $2, $3);",
+                stack_id,
+                kind,
+                representative_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            stack_id
+        }
+    };
+
+    query!(
+        "UPDATE image SET stack_id = $1 WHERE id = ANY($2);",
+        stack_id,
+        &members.iter().map(|m| m.id).collect::<Vec<_>>()
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let previous = members
+        .iter()
+        .filter_map(|m| m.stack_id)
+        .filter(|id| *id != stack_id)
+        .collect::<Vec<_>>();
+    if !previous.is_empty() {
+        dissolve_leftover_stacks(&previous, &mut tx).await?;
+    }
+
+    tx.commit().await?;
+    info!(message = "stacked images", %stack_id, %kind, members = members.len());
+
+    Ok(())
+}
+
+/// Clean up stacks that lost members to another stack
+///
+/// A stack left with a single image is no stack anymore and one left without its
+/// representative gets its earliest image, so the rest stays visible in search.
+async fn dissolve_leftover_stacks<'c>(
+    stack_ids: &[Uuid],
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    query!(
+        "
+            UPDATE image SET stack_id = NULL
+            WHERE stack_id IN (
+                SELECT stack_id FROM image WHERE stack_id = ANY($1)
+                GROUP BY stack_id HAVING COUNT(*) < 2
+            );
+        ",
+        stack_ids
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    let deleted = query!(
+        "
+            DELETE FROM stack
+            WHERE id = ANY($1) AND NOT EXISTS (SELECT 1 FROM image WHERE image.stack_id = stack.id);
+        ",
+        stack_ids
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    query!(
+        "
+            UPDATE stack SET representative_id = (
+                SELECT id FROM image WHERE image.stack_id = stack.id ORDER BY captured_at, id LIMIT 1
+            )
+            WHERE id = ANY($1) AND NOT EXISTS (
+                SELECT 1 FROM image WHERE image.stack_id = stack.id AND image.id = stack.representative_id
+            );
+        ",
+        stack_ids
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    if deleted.rows_affected() > 0 {
+        info!(
+            message = "dissolved emptied stacks",
+            stacks = deleted.rows_affected()
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct StackMember {
+    id: Uuid,
+    file_name: String,
+    captured_at: String,
+    aspect_ratio: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct StackResponse {
+    id: Uuid,
+    kind: String,
+    representative_id: Option<Uuid>,
+    members: Vec<StackMember>,
+}
+
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    stack_id = %stack_id,
+))]
+pub async fn get_stack(
+    account: AuthenticatedAccount,
+    Path(stack_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<Json<StackResponse>, AppError> {
+    info!(message = "get stack");
+
+    #[derive(FromRow)]
+    struct Stack {
+        id: Uuid,
+        kind: String,
+        representative_id: Option<Uuid>,
+    }
+
+    let result = query_as!(
+        Stack,
+        "SELECT id, kind, representative_id FROM stack WHERE id = $1;",
+        stack_id
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+
+    let Some(stack) = result else {
+        warn!(message = "stack doesn't exist");
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    };
+
+    #[derive(FromRow)]
+    struct Member {
+        id: Uuid,
+        filename: String,
+        captured_at: String,
+        aspect_ratio: Option<f64>,
+    }
+
+    let members = query_as!(
+        Member,
+        "
+            SELECT id, filename, captured_at, aspect_ratio
+            FROM image
+            WHERE stack_id = $1
+            ORDER BY captured_at, filename;
+        ",
+        stack_id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|member| StackMember {
+        id: member.id,
+        file_name: path::Path::new(&member.filename)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        captured_at: member.captured_at,
+        aspect_ratio: member.aspect_ratio,
+    })
+    .collect();
+
+    Ok(Json(StackResponse {
+        id: stack.id,
+        kind: stack.kind,
+        representative_id: stack.representative_id,
+        members,
+    }))
+}
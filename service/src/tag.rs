@@ -1,18 +1,244 @@
-use crate::{auth::AuthenticatedAccount, error::AppError};
-use axum::{extract::State, http::StatusCode, Json};
-use serde::Deserialize;
-use sqlx::{query, query_as, Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    auth::AuthenticatedAccount, error::AppError, library::LibraryRoot, query::escape_like,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow, Pool, Postgres, Transaction};
 use tracing::{error, info};
 use uuid::Uuid;
 
 use crate::AppState;
 
+const DEFAULT_SUGGESTIONS: i64 = 10;
+const MAX_SUGGESTIONS: i64 = 50;
+
 #[derive(Deserialize)]
 pub struct TagChangeRequest {
     pub image_ids: Vec<Uuid>,
+    /// Tag paths, like `places/france/lyon`
     pub tags: Vec<String>,
 }
 
+#[derive(Deserialize, Debug)]
+pub struct SuggestParams {
+    q: String,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct TagSuggestion {
+    id: Uuid,
+    description: String,
+    path: String,
+    /// Number of images with the tag
+    count: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct SuggestResponse {
+    tags: Vec<TagSuggestion>,
+}
+
+/// Order of the tag list
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TagSort {
+    /// Alphabetically by path, so children follow their parent
+    #[default]
+    Name,
+    /// Most used first
+    Count,
+    /// Most recently photographed first
+    Recent,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListTagsParams {
+    #[serde(default)]
+    sort: TagSort,
+}
+
+#[derive(Serialize, FromRow)]
+pub struct TagSummary {
+    id: Uuid,
+    parent_id: Option<Uuid>,
+    description: String,
+    path: String,
+    /// Images with the tag or one of its descendants
+    count: Option<i64>,
+    first_captured_at: Option<String>,
+    last_captured_at: Option<String>,
+    /// The most recent image with the tag or one of its descendants
+    representative_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+pub struct ListTagsResponse {
+    tags: Vec<TagSummary>,
+}
+
+/// Normalize a tag path like `Places / France/Lyon` to `places/france/lyon`
+pub fn normalize_tag_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| segment.trim().to_lowercase())
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Find the tag at `path`, creating it and any missing ancestors
+///
+/// Aliases are resolved at every level, either for the path so far or for a segment naming a
+/// child of the tag above it: with `nyc` an alias of `travel/new york`, `travel/nyc` is
+/// `travel/new york`.
+pub async fn resolve_tag_path<'c>(
+    path: &str,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<Option<Uuid>, AppError> {
+    let mut parent: Option<(Uuid, String)> = None;
+
+    for segment in normalize_tag_path(path)
+        .split('/')
+        .filter(|s| !s.is_empty())
+    {
+        let path = match &parent {
+            Some((_, parent_path)) => format!("{}/{}", parent_path, segment),
+            None => segment.to_string(),
+        };
+
+        // `nyc/central park` goes below the tag `nyc` is an alias of
+        let alias = query!(
+            "
+                SELECT tag.id, tag.path
+                FROM tag_alias JOIN tag ON tag.id = tag_alias.tag_id
+                WHERE tag_alias.alias = $1
+                    OR (tag_alias.alias = $2 AND tag.parent_id IS NOT DISTINCT FROM $3)
+                ORDER BY tag_alias.alias = $1 DESC
+                LIMIT 1;
+            ",
+            path,
+            segment,
+            parent.as_ref().map(|(id, _)| *id)
+        )
+        .fetch_optional(&mut **tx)
+        .await?;
+        if let Some(alias) = alias {
+            parent = Some((alias.id, alias.path));
+            continue;
+        }
+
+        query!(
+            "INSERT INTO tag (id, description, parent_id, path) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+            Uuid::now_v7(),
+            segment,
+            parent.as_ref().map(|(id, _)| *id),
+            path
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        let tag = query!("SELECT id FROM tag WHERE path = $1;", path)
+            .fetch_one(&mut **tx)
+            .await?;
+        parent = Some((tag.id, path));
+    }
+
+    Ok(parent.map(|(id, _)| id))
+}
+
+/// All tags with the number of images and the time span they cover
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    sort = ?params.sort,
+))]
+pub async fn list_tags(
+    account: AuthenticatedAccount,
+    params: Query<ListTagsParams>,
+    State(state): State<AppState>,
+) -> Result<Json<ListTagsResponse>, AppError> {
+    let mut tags = query_as!(
+        TagSummary,
+        "
+            WITH RECURSIVE subtree (ancestor_id, descendant_id) AS (
+                SELECT id, id FROM tag
+                UNION ALL
+                SELECT subtree.ancestor_id, child.id
+                FROM subtree JOIN tag child ON child.parent_id = subtree.descendant_id
+            )
+            SELECT tag.id, tag.parent_id, tag.description, tag.path, COUNT(DISTINCT image.id) count,
+                MIN(image.captured_at) first_captured_at, MAX(image.captured_at) last_captured_at,
+                (ARRAY_AGG(image.id ORDER BY image.captured_at DESC) FILTER (WHERE image.id IS NOT NULL))[1] representative_id
+            FROM tag
+            JOIN subtree ON subtree.ancestor_id = tag.id
+            LEFT JOIN image_tag ON image_tag.tag_id = subtree.descendant_id
+            LEFT JOIN image ON image.id = image_tag.image_id
+            GROUP BY tag.id
+            ORDER BY tag.path;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    match params.sort {
+        TagSort::Name => {}
+        TagSort::Count => tags.sort_by_key(|tag| std::cmp::Reverse(tag.count)),
+        TagSort::Recent => tags.sort_by(|a, b| b.last_captured_at.cmp(&a.last_captured_at)),
+    }
+
+    info!(message = "listed tags", tags = tags.len());
+
+    Ok(Json(ListTagsResponse { tags }))
+}
+
+/// Tags for an autocomplete box: those starting with `q` first, then those similar to it
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    q = %params.q,
+))]
+pub async fn suggest_tags(
+    account: AuthenticatedAccount,
+    params: Query<SuggestParams>,
+    State(state): State<AppState>,
+) -> Result<Json<SuggestResponse>, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_SUGGESTIONS);
+    if !(1..=MAX_SUGGESTIONS).contains(&limit) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            format!("limit must be between 1 and {}", MAX_SUGGESTIONS),
+        ));
+    }
+
+    let q = params.q.trim().to_lowercase();
+
+    // `q` may be a name, an alias or the start of a path, like `places/fr`
+    let tags = query_as!(
+        TagSuggestion,
+        "
+            SELECT tag.id, tag.description, tag.path, COUNT(image_tag.image_id) count
+            FROM tag
+            LEFT JOIN image_tag ON image_tag.tag_id = tag.id
+            WHERE tag.description LIKE $1 OR tag.path LIKE $1 OR tag.description % $2
+                OR tag.id IN (SELECT tag_id FROM tag_alias WHERE alias LIKE $1)
+            GROUP BY tag.id
+            ORDER BY (tag.description LIKE $1 OR tag.path LIKE $1 OR tag.id IN (SELECT tag_id FROM tag_alias WHERE alias LIKE $1)) DESC, SIMILARITY(tag.description, $2) DESC, count DESC, tag.path
+            LIMIT $3;
+        ",
+        format!("{}%", escape_like(&q)),
+        q,
+        limit
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(SuggestResponse { tags }))
+}
+
 #[tracing::instrument(skip_all, fields(
     username = %account.username,
 ))]
@@ -32,10 +258,14 @@ pub async fn add_tags_handler(
     }
 
     let mut tx = state.pool.begin().await?;
+    let image_ids = body.image_ids.clone();
 
     match add_tags(body, &mut tx).await {
         Ok(()) => {
             tx.commit().await?;
+            if let Some(sidecars) = &state.sidecars {
+                sidecars.queue(&image_ids);
+            }
             Ok(StatusCode::OK)
         }
         Err(error) => Err(error),
@@ -56,43 +286,20 @@ pub async fn add_tags<'c>(
         return Err(AppError::Status(StatusCode::BAD_REQUEST));
     }
 
-    let tags = body
-        .tags
-        .iter()
-        .map(|t| t.to_lowercase())
-        .collect::<Vec<_>>();
-
-    // upsert tags to ensure they exist
-    for tag in &tags {
-        query!(
-            "INSERT INTO tag (id, description) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-            Uuid::now_v7(),
-            tag,
-        )
-        .execute(&mut **tx)
-        .await?;
-    }
-
-    #[derive(Debug)]
-    struct Tag {
-        id: Uuid,
+    // upsert tags, with their ancestors, to ensure they exist
+    let mut tags = vec![];
+    for tag in &body.tags {
+        if let Some(id) = resolve_tag_path(tag, tx).await? {
+            tags.push(id);
+        }
     }
 
-    // get tags from db
-    let tags = query_as!(
-        Tag,
-        "SELECT id FROM tag WHERE description = ANY($1);",
-        &tags
-    )
-    .fetch_all(&mut **tx)
-    .await?;
-
     // upsert image_tag relations
     for tag in tags {
         for image in &body.image_ids {
             query!(
                 "INSERT INTO image_tag (tag_id, image_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
-                tag.id,
+                tag,
                 image
             )
             .execute(&mut **tx)
@@ -114,23 +321,554 @@ pub async fn remove_tags(
     info!(message = "Removing tags from images");
     let mut tx = state.pool.begin().await?;
 
+    let paths = body
+        .tags
+        .iter()
+        .map(|tag| normalize_tag_path(tag))
+        .collect::<Vec<_>>();
+
     let deleted_relations = query!(
-        "DELETE FROM image_tag USING tag WHERE image_tag.tag_id = tag.id AND image_tag.image_id = ANY($1) AND tag.description = ANY($2);",
+        "DELETE FROM image_tag USING tag WHERE image_tag.tag_id = tag.id AND image_tag.image_id = ANY($1) AND tag.path = ANY($2);",
         &body.image_ids,
-        &body.tags
+        &paths
     ).execute(&mut *tx).await?;
     info!(deleted_relations = %deleted_relations.rows_affected());
 
-    let deleted_tags = query!(
-        "DELETE FROM tag WHERE id NOT IN (SELECT tag_id FROM image_tag) AND description = ANY($1);",
-        &body.tags
+    let deleted_tags = delete_unused_tags(&paths, &mut tx).await?;
+
+    info!(deleted_tags = %deleted_tags);
+
+    tx.commit().await?;
+
+    if let Some(sidecars) = &state.sidecars {
+        sidecars.queue(&body.image_ids);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Delete the tags at `paths` that are on no image and have no children or aliases
+///
+/// Their ancestors are deleted too once they are left the same way, as they were mostly
+/// only created to hold the path.
+pub async fn delete_unused_tags<'c>(
+    paths: &[String],
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<u64, AppError> {
+    let paths = paths
+        .iter()
+        .flat_map(|path| {
+            path.match_indices('/')
+                .map(|(index, _)| path[..index].to_string())
+                .chain([path.clone()])
+        })
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    // Each round deletes the leaves, which can leave their parents without children
+    let mut deleted = 0;
+    loop {
+        let round = query!(
+            "
+                DELETE FROM tag
+                WHERE path = ANY($1)
+                    AND NOT EXISTS (SELECT 1 FROM image_tag WHERE image_tag.tag_id = tag.id)
+                    AND NOT EXISTS (SELECT 1 FROM tag child WHERE child.parent_id = tag.id)
+                    AND NOT EXISTS (SELECT 1 FROM tag_alias WHERE tag_alias.tag_id = tag.id);
+            ",
+            &paths
+        )
+        .execute(&mut **tx)
+        .await?;
+        if round.rows_affected() == 0 {
+            return Ok(deleted);
+        }
+        deleted += round.rows_affected();
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RenameTagBody {
+    /// New name, keeping the parent
+    description: Option<String>,
+    /// New full path, moving the tag and its descendants under another parent
+    path: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MergeTagsBody {
+    /// Tags folded into the target and deleted
+    tag_ids: Vec<Uuid>,
+    target_id: Uuid,
+}
+
+#[derive(Serialize)]
+pub struct TagResponse {
+    id: Uuid,
+    description: String,
+    path: String,
+    /// Whether other tags were folded into this one
+    merged: bool,
+}
+
+/// Rename or move a tag, merging it into the tag that already has the new path, if any
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    tag_id = %tag_id,
+))]
+pub async fn rename_tag(
+    account: AuthenticatedAccount,
+    Path(tag_id): Path<Uuid>,
+    State(state): State<AppState>,
+    Json(body): Json<RenameTagBody>,
+) -> Result<Json<TagResponse>, AppError> {
+    let mut tx = state.pool.begin().await?;
+
+    let Some(tag) = query!(
+        "SELECT id, parent_id, path FROM tag WHERE id = $1 FOR UPDATE;",
+        tag_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    };
+
+    // Tags are lowercase, as the `tag_description_lowercase_ck` constraint enforces
+    let path = match (&body.description, &body.path) {
+        (Some(description), None) => {
+            let description = normalize_tag_path(description);
+            if description.is_empty() || description.contains('/') {
+                return Err(AppError::Text(
+                    StatusCode::BAD_REQUEST,
+                    "description must be a single non-empty name, use path to move a tag"
+                        .to_string(),
+                ));
+            }
+            match tag.parent_id {
+                Some(parent_id) => {
+                    let parent = query!("SELECT path FROM tag WHERE id = $1;", parent_id)
+                        .fetch_one(&mut *tx)
+                        .await?;
+                    format!("{}/{}", parent.path, description)
+                }
+                None => description,
+            }
+        }
+        (None, Some(path)) => normalize_tag_path(path),
+        _ => {
+            return Err(AppError::Text(
+                StatusCode::BAD_REQUEST,
+                "expected either description or path".to_string(),
+            ))
+        }
+    };
+    if path.is_empty() {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "path must not be empty".to_string(),
+        ));
+    }
+    if path.starts_with(&format!("{}/", tag.path)) {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "a tag cannot be moved below itself".to_string(),
+        ));
+    }
+
+    let (parent_path, description) = match path.rsplit_once('/') {
+        Some((parent_path, description)) => (Some(parent_path), description.to_string()),
+        None => (None, path.clone()),
+    };
+
+    let alias = query!("SELECT id FROM tag_alias WHERE alias = $1;", path)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if alias.is_some() {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            format!("{} is an alias, delete it first", path),
+        ));
+    }
+
+    let image_ids = subtree_images(std::slice::from_ref(&tag.path), &mut tx).await?;
+
+    let existing = query!(
+        "SELECT id FROM tag WHERE path = $1 AND id <> $2 FOR UPDATE;",
+        path,
+        tag_id
     )
-    .execute(&mut *tx)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    info!(deleted_tags = %deleted_tags.rows_affected());
+    let id = match existing {
+        Some(existing) => {
+            info!(message = "merging renamed tag into existing tag", target_id = %existing.id);
+            merge_into(&[tag_id], existing.id, &mut tx).await?;
+            existing.id
+        }
+        None => {
+            let parent_id = match parent_path {
+                Some(parent_path) => resolve_tag_path(parent_path, &mut tx).await?,
+                None => None,
+            };
+            move_subtree(tag_id, &tag.path, parent_id, &description, &path, &mut tx).await?;
+            tag_id
+        }
+    };
 
     tx.commit().await?;
 
-    Ok(StatusCode::OK)
+    if let Some(sidecars) = &state.sidecars {
+        sidecars.queue(&image_ids);
+    }
+
+    info!(message = "renamed tag", images = image_ids.len());
+
+    Ok(Json(TagResponse {
+        id,
+        description,
+        path,
+        merged: id != tag_id,
+    }))
+}
+
+/// Fold several tags into one, keeping a single `image_tag` row per image
+///
+/// Children of the merged tags move under the target, merging with its children of the same
+/// name.
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    target_id = %body.target_id,
+))]
+pub async fn merge_tags(
+    account: AuthenticatedAccount,
+    State(state): State<AppState>,
+    Json(body): Json<MergeTagsBody>,
+) -> Result<Json<TagResponse>, AppError> {
+    let mut sources = body
+        .tag_ids
+        .iter()
+        .copied()
+        .filter(|id| *id != body.target_id)
+        .collect::<Vec<_>>();
+    // Listing a tag twice would count as missing one
+    sources.sort();
+    sources.dedup();
+    if sources.is_empty() {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "tag_ids must name at least one tag besides the target".to_string(),
+        ));
+    }
+
+    let mut tx = state.pool.begin().await?;
+
+    let Some(target) = query!(
+        "SELECT id, description, path FROM tag WHERE id = $1 FOR UPDATE;",
+        body.target_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    };
+
+    let found = query!(
+        "SELECT id, path FROM tag WHERE id = ANY($1) FOR UPDATE;",
+        &sources
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if found.len() != sources.len() {
+        return Err(AppError::Text(
+            StatusCode::NOT_FOUND,
+            "some tags do not exist".to_string(),
+        ));
+    }
+    if found
+        .iter()
+        .any(|source| target.path.starts_with(&format!("{}/", source.path)))
+    {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "a tag cannot be merged into one of its descendants".to_string(),
+        ));
+    }
+
+    let paths = found
+        .into_iter()
+        .map(|source| source.path)
+        .collect::<Vec<_>>();
+    let image_ids = subtree_images(&paths, &mut tx).await?;
+
+    merge_into(&sources, target.id, &mut tx).await?;
+
+    tx.commit().await?;
+
+    if let Some(sidecars) = &state.sidecars {
+        sidecars.queue(&image_ids);
+    }
+
+    info!(
+        message = "merged tags",
+        tags = sources.len(),
+        images = image_ids.len()
+    );
+
+    Ok(Json(TagResponse {
+        id: target.id,
+        description: target.description,
+        path: target.path,
+        merged: true,
+    }))
+}
+
+/// Images tagged with one of `paths` or their descendants
+async fn subtree_images<'c>(
+    paths: &[String],
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<Vec<Uuid>, AppError> {
+    let images = query!(
+        "
+            SELECT DISTINCT image_tag.image_id
+            FROM image_tag
+            JOIN tag ON tag.id = image_tag.tag_id
+            JOIN UNNEST($1::text[]) root(path) ON tag.path = root.path OR STARTS_WITH(tag.path, root.path || '/');
+        ",
+        paths
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(images
+        .into_iter()
+        .filter_map(|image| image.image_id)
+        .collect())
+}
+
+/// Give a tag a new parent, name and path, and rewrite the paths of its descendants to match
+async fn move_subtree<'c>(
+    tag_id: Uuid,
+    old_path: &str,
+    parent_id: Option<Uuid>,
+    description: &str,
+    path: &str,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    query!(
+        "UPDATE tag SET parent_id = $2, description = $3, path = $4 WHERE id = $1;",
+        tag_id,
+        parent_id,
+        description,
+        path
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    query!(
+        "UPDATE tag SET path = $2 || SUBSTRING(path FROM LENGTH($1) + 1) WHERE STARTS_WITH(path, $1 || '/');",
+        old_path,
+        path
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Move the images, children and aliases of `sources` to `target` and delete `sources`
+///
+/// A child whose name the target already has is merged into the target's child the same way.
+async fn merge_into<'c>(
+    sources: &[Uuid],
+    target: Uuid,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    let mut pending = sources
+        .iter()
+        .map(|source| (*source, target))
+        .collect::<Vec<_>>();
+    let mut merged = vec![];
+
+    while let Some((source, target)) = pending.pop() {
+        let target_path = query!("SELECT path FROM tag WHERE id = $1;", target)
+            .fetch_one(&mut **tx)
+            .await?
+            .path;
+
+        let children = query!(
+            "SELECT id, description, path FROM tag WHERE parent_id = $1;",
+            source
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+        for child in children {
+            let path = format!("{}/{}", target_path, child.description);
+            let existing = query!("SELECT id FROM tag WHERE path = $1;", path)
+                .fetch_optional(&mut **tx)
+                .await?;
+            match existing {
+                Some(existing) => pending.push((child.id, existing.id)),
+                None => {
+                    move_subtree(
+                        child.id,
+                        &child.path,
+                        Some(target),
+                        &child.description,
+                        &path,
+                        tx,
+                    )
+                    .await?
+                }
+            }
+        }
+
+        // Images that already have the target keep their row, the conflict skips the duplicate
+        query!(
+            "
+                INSERT INTO image_tag (tag_id, image_id)
+                SELECT $2, image_id FROM image_tag WHERE tag_id = $1
+                ON CONFLICT DO NOTHING;
+            ",
+            source,
+            target
+        )
+        .execute(&mut **tx)
+        .await?;
+        query!("DELETE FROM image_tag WHERE tag_id = $1;", source)
+            .execute(&mut **tx)
+            .await?;
+        query!(
+            "UPDATE tag_alias SET tag_id = $2 WHERE tag_id = $1;",
+            source,
+            target
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        merged.push(source);
+    }
+
+    // Children are merged after their parents, so they go first
+    for source in merged.iter().rev() {
+        query!("DELETE FROM tag WHERE id = $1;", source)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Replace the flat folder tags of images indexed before tags had parents with the folder path
+///
+/// Such an image in `france/lyon` has the root tags `france` and `lyon`, and gets
+/// `france/lyon` instead, which already implies `france`. Only tags never used outside a
+/// folder of that name count as folder tags, a `lyon` added to other images too is left alone.
+///
+/// Runs once, after the scan that assigned images indexed back then to their libraries.
+#[tracing::instrument(skip_all)]
+pub async fn nest_folder_tags(
+    pool: &Pool<Postgres>,
+    libraries: &[LibraryRoot],
+) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+
+    let pending = query!("DELETE FROM pending_task WHERE name = 'nest_folder_tags';")
+        .execute(&mut *tx)
+        .await?;
+    if pending.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    let images = query!(
+        "
+            SELECT image.id, image.filename, image.library, ARRAY_AGG(tag.path) paths
+            FROM image
+            JOIN image_tag ON image_tag.image_id = image.id
+            JOIN tag ON tag.id = image_tag.tag_id
+            WHERE tag.parent_id IS NULL
+            GROUP BY image.id;
+        "
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    // Whether each root tag is only on images in a folder of that name
+    let mut folder_only: HashMap<String, bool> = HashMap::new();
+    let mut folders = vec![];
+    for image in images {
+        let folder = libraries
+            .iter()
+            .find(|library| Some(&library.name) == image.library.as_ref())
+            .and_then(|library| {
+                let path = library
+                    .folder_tags(std::path::Path::new(&image.filename))
+                    .pop()?;
+                let path = normalize_tag_path(&path);
+                let flat = flat_folder_tags(&path, &library.folder_tags.prefix);
+                Some((path, flat))
+            });
+        let paths = image.paths.unwrap_or_default();
+
+        for tag in &paths {
+            let in_folder = folder.as_ref().is_some_and(|(_, flat)| flat.contains(tag));
+            *folder_only.entry(tag.clone()).or_insert(true) &= in_folder;
+        }
+        if let Some((path, flat)) = folder {
+            folders.push((image.id, path, flat, paths));
+        }
+    }
+
+    let mut nested = 0;
+    let mut replaced = HashSet::new();
+    for (image_id, path, flat, paths) in folders {
+        let flat = flat
+            .into_iter()
+            .filter(|tag| *tag != path && paths.contains(tag) && folder_only[tag])
+            .collect::<Vec<_>>();
+        if flat.is_empty() {
+            continue;
+        }
+
+        add_tags(
+            TagChangeRequest {
+                image_ids: vec![image_id],
+                tags: vec![path],
+            },
+            &mut tx,
+        )
+        .await?;
+        query!(
+            "DELETE FROM image_tag USING tag WHERE image_tag.tag_id = tag.id AND image_tag.image_id = $1 AND tag.path = ANY($2);",
+            image_id,
+            &flat
+        )
+        .execute(&mut *tx)
+        .await?;
+        replaced.extend(flat);
+
+        nested += 1;
+    }
+
+    delete_unused_tags(&replaced.into_iter().collect::<Vec<_>>(), &mut tx).await?;
+    tx.commit().await?;
+
+    info!(message = "nested folder tags", images = nested);
+
+    Ok(())
+}
+
+/// Tags the folders of `path` got before tags had parents, each with the library prefix
+///
+/// The root folder's tag is the same as the root of `path`.
+fn flat_folder_tags(path: &str, prefix: &str) -> Vec<String> {
+    path.split('/')
+        .enumerate()
+        .map(|(index, segment)| match index {
+            0 => segment.to_string(),
+            _ => normalize_tag_path(&format!("{}{}", prefix, segment)),
+        })
+        .collect()
 }
@@ -0,0 +1,213 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, FromRow, Pool, Postgres};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{auth::AuthenticatedAccount, error::AppError, tag::normalize_tag_path, AppState};
+
+/// Another name for a tag, like `nyc` for `places/new york`
+///
+/// Adding or searching for an alias resolves to its tag.
+#[derive(Serialize, FromRow)]
+pub struct TagAlias {
+    id: Uuid,
+    alias: String,
+    tag_id: Uuid,
+    path: String,
+}
+
+#[derive(Serialize)]
+pub struct ListAliasesResponse {
+    aliases: Vec<TagAlias>,
+}
+
+#[derive(Deserialize)]
+pub struct AliasBody {
+    alias: String,
+    tag_id: Uuid,
+}
+
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+))]
+pub async fn list_aliases(
+    account: AuthenticatedAccount,
+    State(state): State<AppState>,
+) -> Result<Json<ListAliasesResponse>, AppError> {
+    let aliases = query_as!(
+        TagAlias,
+        "
+            SELECT tag_alias.id, tag_alias.alias, tag_alias.tag_id, tag.path
+            FROM tag_alias JOIN tag ON tag.id = tag_alias.tag_id
+            ORDER BY tag_alias.alias;
+        "
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    Ok(Json(ListAliasesResponse { aliases }))
+}
+
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    tag_id = %body.tag_id,
+))]
+pub async fn create_alias(
+    account: AuthenticatedAccount,
+    State(state): State<AppState>,
+    Json(body): Json<AliasBody>,
+) -> Result<Json<TagAlias>, AppError> {
+    let id = Uuid::now_v7();
+    let (alias, path) = validate_alias(&body, id, &state.pool).await?;
+
+    query!(
+        "INSERT INTO tag_alias (id, alias, tag_id) VALUES ($1, $2, $3);",
+        id,
+        alias,
+        body.tag_id
+    )
+    .execute(&state.pool)
+    .await
+    .map_err(|error| duplicate_alias(error, &alias))?;
+
+    info!(message = "created tag alias", alias);
+
+    Ok(Json(TagAlias {
+        id,
+        alias,
+        tag_id: body.tag_id,
+        path,
+    }))
+}
+
+/// Rename an alias or point it at another tag
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    alias_id = %alias_id,
+))]
+pub async fn update_alias(
+    account: AuthenticatedAccount,
+    Path(alias_id): Path<Uuid>,
+    State(state): State<AppState>,
+    Json(body): Json<AliasBody>,
+) -> Result<Json<TagAlias>, AppError> {
+    let (alias, path) = validate_alias(&body, alias_id, &state.pool).await?;
+
+    let updated = query!(
+        "UPDATE tag_alias SET alias = $2, tag_id = $3 WHERE id = $1;",
+        alias_id,
+        alias,
+        body.tag_id
+    )
+    .execute(&state.pool)
+    .await
+    .map_err(|error| duplicate_alias(error, &alias))?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    }
+
+    info!(message = "updated tag alias", alias);
+
+    Ok(Json(TagAlias {
+        id: alias_id,
+        alias,
+        tag_id: body.tag_id,
+        path,
+    }))
+}
+
+#[tracing::instrument(skip_all, fields(
+    username = %account.username,
+    alias_id = %alias_id,
+))]
+pub async fn delete_alias(
+    account: AuthenticatedAccount,
+    Path(alias_id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AppError> {
+    let deleted = query!("DELETE FROM tag_alias WHERE id = $1;", alias_id)
+        .execute(&state.pool)
+        .await?;
+    if deleted.rows_affected() == 0 {
+        return Err(AppError::Status(StatusCode::NOT_FOUND));
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Normalize the alias like a tag path and check it can name the tag, returning the alias and
+/// the tag's path
+async fn validate_alias(
+    body: &AliasBody,
+    alias_id: Uuid,
+    pool: &Pool<Postgres>,
+) -> Result<(String, String), AppError> {
+    let alias = normalize_tag_path(&body.alias);
+    if alias.is_empty() {
+        return Err(AppError::Text(
+            StatusCode::BAD_REQUEST,
+            "alias must not be empty".to_string(),
+        ));
+    }
+
+    let Some(tag) = query!(
+        "SELECT path, parent_id FROM tag WHERE id = $1;",
+        body.tag_id
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Err(AppError::Text(
+            StatusCode::NOT_FOUND,
+            "tag does not exist".to_string(),
+        ));
+    };
+
+    // Adding the path would be ambiguous between the tag and the alias, also below the tag's
+    // parent where the alias stands for the tag's name
+    let taken = query!(
+        "SELECT id FROM tag WHERE path = $1 OR (description = $1 AND parent_id = $2);",
+        alias,
+        tag.parent_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    if taken.is_some() {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            format!("a tag named {} exists, merge it instead", alias),
+        ));
+    }
+
+    let duplicate = query!(
+        "SELECT id FROM tag_alias WHERE alias = $1 AND id <> $2;",
+        alias,
+        alias_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    if duplicate.is_some() {
+        return Err(AppError::Text(
+            StatusCode::CONFLICT,
+            format!("{} is already an alias", alias),
+        ));
+    }
+
+    Ok((alias, tag.path))
+}
+
+/// The alias was added concurrently after `validate_alias` checked it
+fn duplicate_alias(error: sqlx::Error, alias: &str) -> AppError {
+    match error.as_database_error() {
+        Some(database_error) if database_error.is_unique_violation() => AppError::Text(
+            StatusCode::CONFLICT,
+            format!("{} is already an alias", alias),
+        ),
+        _ => AppError::DBError(error),
+    }
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    path::Path,
+    process::Command,
+};
+
+use axum::http::StatusCode;
+use image::{DynamicImage, ImageReader};
+use jiff::Timestamp;
+use serde_json::Value;
+use tracing::info;
+
+use crate::error::AppError;
+
+/// Container metadata of a video as reported by `ffprobe`
+pub struct VideoInfo {
+    pub width: u32,
+    pub height: u32,
+    pub duration: Option<f64>,
+    pub created_at: Option<Timestamp>,
+    pub metadata: HashMap<String, String>,
+}
+
+pub async fn probe(path: &Path) -> Result<VideoInfo, AppError> {
+    let mut command = tokio::process::Command::new("ffprobe");
+    command
+        .args([
+            "-v",
+            "error",
+            "-print_format",
+            "json",
+            "-show_format",
+            "-show_streams",
+        ])
+        .arg(path);
+    let output = command
+        .output()
+        .await
+        .map_err(|error| not_found(command.as_std(), error))?;
+
+    if !output.status.success() {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("ffprobe exited with {}", output.status),
+        ));
+    }
+
+    let probe: Value = serde_json::from_slice(&output.stdout)?;
+
+    let Some(stream) = probe["streams"]
+        .as_array()
+        .and_then(|streams| streams.iter().find(|s| s["codec_type"] == "video"))
+    else {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "file has no video stream".to_string(),
+        ));
+    };
+
+    let mut width = stream["width"].as_u64().unwrap_or_default() as u32;
+    let mut height = stream["height"].as_u64().unwrap_or_default() as u32;
+
+    // Phones record portrait videos as landscape frames with a rotation flag
+    let rotation = stream["tags"]["rotate"]
+        .as_str()
+        .and_then(|r| r.parse::<i64>().ok())
+        .or_else(|| {
+            stream["side_data_list"]
+                .as_array()
+                .and_then(|side_data| side_data.iter().find_map(|s| s["rotation"].as_i64()))
+        })
+        .unwrap_or_default();
+    if rotation.abs() % 180 == 90 {
+        std::mem::swap(&mut width, &mut height);
+    }
+
+    let format = &probe["format"];
+    let duration = format["duration"]
+        .as_str()
+        .and_then(|d| d.parse::<f64>().ok());
+    let created_at = format["tags"]["creation_time"]
+        .as_str()
+        .and_then(|c| c.parse::<Timestamp>().ok());
+
+    let mut metadata = HashMap::new();
+    if let Some(tags) = format["tags"].as_object() {
+        for (key, value) in tags {
+            if let Some(value) = value.as_str() {
+                metadata.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+    for key in ["codec_name", "width", "height", "r_frame_rate"] {
+        match &stream[key] {
+            Value::Null => {}
+            Value::String(value) => {
+                metadata.insert(key.to_string(), value.to_string());
+            }
+            value => {
+                metadata.insert(key.to_string(), value.to_string());
+            }
+        }
+    }
+    if let Some(duration) = duration {
+        metadata.insert("duration".to_string(), duration.to_string());
+    }
+
+    Ok(VideoInfo {
+        width,
+        height,
+        duration,
+        created_at,
+        metadata,
+    })
+}
+
+/// Grab a frame one second into the video to stand in for it in the grid
+///
+/// Blocks until ffmpeg is done, run it through `decode_image_blocking` from async code.
+pub fn poster_frame(path: &Path) -> Result<DynamicImage, AppError> {
+    info!(message = "extracting poster frame", file = ?path);
+
+    let mut frame = extract_frame(path, Some("1"))?;
+    // Clips shorter than a second have nothing to seek to
+    if frame.is_empty() {
+        frame = extract_frame(path, None)?;
+    }
+
+    if frame.is_empty() {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "ffmpeg produced no poster frame".to_string(),
+        ));
+    }
+
+    Ok(ImageReader::new(Cursor::new(frame))
+        .with_guessed_format()?
+        .decode()?)
+}
+
+fn extract_frame(path: &Path, seek: Option<&str>) -> Result<Vec<u8>, AppError> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-v", "error"]);
+    if let Some(seek) = seek {
+        command.args(["-ss", seek]);
+    }
+    command
+        .arg("-i")
+        .arg(path)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"]);
+    let output = command
+        .output()
+        .map_err(|error| not_found(&command, error))?;
+
+    if !output.status.success() {
+        return Err(AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("ffmpeg exited with {}", output.status),
+        ));
+    }
+
+    Ok(output.stdout)
+}
+
+fn not_found(command: &Command, error: io::Error) -> AppError {
+    match error.kind() {
+        io::ErrorKind::NotFound => AppError::Text(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("{} is not on PATH", command.get_program().to_string_lossy()),
+        ),
+        _ => error.into(),
+    }
+}
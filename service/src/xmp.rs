@@ -0,0 +1,475 @@
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use jiff::Timestamp;
+use quick_xml::{events::Event, Reader};
+use sqlx::{query, Postgres, Transaction};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    error::AppError,
+    tag::{add_tags, delete_unused_tags, normalize_tag_path, TagChangeRequest},
+    AppState,
+};
+
+/// Embedded XMP and IPTC sit in the first segments of a file, RAW and video data follows
+const EMBEDDED_METADATA_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Keywords, rating and caption curated in Lightroom, darktable and friends
+#[derive(Debug, Default)]
+pub struct CuratedMetadata {
+    pub keywords: Vec<String>,
+    pub rating: Option<i32>,
+    pub caption: Option<String>,
+    pub sidecar_modified_at: Option<String>,
+    /// Keywords of the sidecar alone, to tell which were removed from it on the next import
+    pub sidecar_keywords: Vec<String>,
+}
+
+impl CuratedMetadata {
+    /// Fill in from `other` whatever is still missing, keywords are merged
+    fn merge(&mut self, other: CuratedMetadata) {
+        for keyword in other.keywords {
+            if !self.keywords.contains(&keyword) {
+                self.keywords.push(keyword);
+            }
+        }
+        self.rating = self.rating.or(other.rating);
+        self.caption = self.caption.take().or(other.caption);
+        self.sidecar_modified_at = self
+            .sidecar_modified_at
+            .take()
+            .or(other.sidecar_modified_at);
+    }
+}
+
+/// Find the XMP sidecar of `file`
+///
+/// darktable writes `IMG_0001.NEF.xmp`, Lightroom writes `IMG_0001.xmp`.
+pub fn sidecar_path(file: &Path) -> Option<PathBuf> {
+    let mut candidates = vec![];
+    for extension in ["xmp", "XMP"] {
+        let mut appended = file.as_os_str().to_owned();
+        appended.push(".");
+        appended.push(extension);
+        candidates.push(PathBuf::from(appended));
+        candidates.push(file.with_extension(extension));
+    }
+
+    candidates
+        .into_iter()
+        .find(|candidate| candidate != file && candidate.is_file())
+}
+
+pub fn sidecar_modified_at(sidecar: &Path) -> Result<String, AppError> {
+    let modified: Timestamp = fs::metadata(sidecar)?.modified()?.try_into()?;
+    Ok(modified.to_string())
+}
+
+/// Read the curated metadata of `file` from its sidecar, embedded XMP and embedded IPTC
+///
+/// The sidecar takes precedence, as that's where editors write changes to.
+pub fn read_curated_metadata(file: &Path) -> Result<CuratedMetadata, AppError> {
+    let mut metadata = match sidecar_path(file) {
+        Some(sidecar) => {
+            let mut metadata = parse_xmp(&fs::read_to_string(&sidecar)?)?;
+            metadata.sidecar_modified_at = Some(sidecar_modified_at(&sidecar)?);
+            metadata.sidecar_keywords = metadata.keywords.clone();
+            metadata
+        }
+        None => CuratedMetadata::default(),
+    };
+
+    let mut data = vec![];
+    fs::File::open(file)?
+        .take(EMBEDDED_METADATA_BYTES)
+        .read_to_end(&mut data)?;
+    if let Some(packet) = embedded_xmp(&data) {
+        metadata.merge(parse_xmp(&String::from_utf8_lossy(packet))?);
+    }
+    metadata.merge(parse_iptc(&data));
+
+    Ok(metadata)
+}
+
+/// Set the image's rating, caption and tags to `metadata`
+///
+/// The sidecar is the newer side when this runs, so a rating or caption it lacks is cleared
+/// and keywords removed from it since the last import are removed from the image. Tags from
+/// anywhere else are kept.
+pub async fn apply_curated_metadata<'c>(
+    image_id: Uuid,
+    metadata: CuratedMetadata,
+    tx: &mut Transaction<'c, Postgres>,
+) -> Result<(), AppError> {
+    let previous = query!(
+        "SELECT sidecar_keywords FROM image WHERE id = $1;",
+        image_id
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    let removed = previous
+        .sidecar_keywords
+        .unwrap_or_default()
+        .iter()
+        .filter(|keyword| !metadata.sidecar_keywords.contains(keyword))
+        .map(|keyword| normalize_tag_path(keyword))
+        .collect::<Vec<_>>();
+
+    if !removed.is_empty() {
+        let deleted = query!(
+            "
+                DELETE FROM image_tag USING tag
+                WHERE image_tag.tag_id = tag.id AND image_tag.image_id = $1
+                    AND (tag.path = ANY($2) OR tag.id IN (SELECT tag_id FROM tag_alias WHERE alias = ANY($2)))
+                RETURNING tag.path;
+            ",
+            image_id,
+            &removed
+        )
+        .fetch_all(&mut **tx)
+        .await?;
+
+        let paths = deleted.into_iter().map(|tag| tag.path).collect::<Vec<_>>();
+        delete_unused_tags(&paths, tx).await?;
+        info!(message = "removed keywords dropped from sidecar", %image_id, tags = paths.len());
+    }
+
+    if !metadata.keywords.is_empty() {
+        add_tags(
+            TagChangeRequest {
+                image_ids: vec![image_id],
+                tags: metadata.keywords,
+            },
+            tx,
+        )
+        .await?;
+    }
+
+    query!(
+        "
+            UPDATE image
+            SET rating = $2, caption = $3, sidecar_modified_at = $4, sidecar_keywords = $5
+            WHERE id = $1;
+        ",
+        image_id,
+        metadata.rating,
+        metadata.caption,
+        metadata.sidecar_modified_at,
+        &metadata.sidecar_keywords
+    )
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Re-import the sidecar of an indexed image if it changed since it was last read
+pub async fn sync_sidecar(state: &AppState, image_id: Uuid, file: &Path) -> Result<(), AppError> {
+    let Some(sidecar) = sidecar_path(file) else {
+        return Ok(());
+    };
+    let modified_at = sidecar_modified_at(&sidecar)?;
+
+    let result = query!(
+        "SELECT sidecar_modified_at FROM image WHERE id = $1;",
+        image_id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+
+    if result.sidecar_modified_at.as_deref() == Some(modified_at.as_str()) {
+        return Ok(());
+    }
+
+    info!(message = "sidecar changed, importing", %image_id);
+
+    let mut metadata = parse_xmp(&fs::read_to_string(&sidecar)?)?;
+    metadata.sidecar_modified_at = Some(modified_at);
+    metadata.sidecar_keywords = metadata.keywords.clone();
+
+    let mut tx = state.pool.begin().await?;
+    apply_curated_metadata(image_id, metadata, &mut tx).await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Parse dc:subject, lr:hierarchicalSubject, xmp:Rating and dc:description of an XMP packet
+///
+/// Only hierarchical keywords become nested tags, a `/` in a flat keyword is taken literally
+/// and replaced by `-`.
+pub fn parse_xmp(xml: &str) -> Result<CuratedMetadata, AppError> {
+    let mut metadata = CuratedMetadata::default();
+    let mut reader = Reader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    // Names of the currently open elements
+    let mut open: Vec<String> = vec![];
+    let mut subjects = vec![];
+    let mut hierarchical = vec![];
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) => {
+                read_rating_attribute(&element, &mut metadata);
+                open.push(String::from_utf8_lossy(element.name().as_ref()).to_string());
+            }
+            Event::Empty(element) => read_rating_attribute(&element, &mut metadata),
+            Event::End(_) => {
+                open.pop();
+            }
+            Event::Text(text) => {
+                let text = text.unescape()?.trim().to_string();
+                if text.is_empty() {
+                    continue;
+                }
+                let inside = |name: &str| open.iter().any(|o| o == name);
+
+                if inside("dc:subject") {
+                    subjects.push(flat_keyword(&text));
+                } else if inside("lr:hierarchicalSubject") {
+                    // `places|france|lyon` becomes the tag path `places/france/lyon`
+                    let path = text
+                        .split('|')
+                        .map(|k| k.trim().replace('/', "-").to_lowercase())
+                        .filter(|k| !k.is_empty())
+                        .collect::<Vec<_>>();
+                    if !path.is_empty() {
+                        hierarchical.push(path);
+                    }
+                } else if inside("dc:description") && metadata.caption.is_none() {
+                    metadata.caption = Some(text);
+                } else if open.last().is_some_and(|o| o == "xmp:Rating") {
+                    metadata.rating = text.parse().ok();
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    // Lightroom also lists every level of a hierarchical keyword in dc:subject, those are
+    // covered by the paths
+    subjects.retain(|subject| {
+        !hierarchical
+            .iter()
+            .any(|path| path.iter().any(|segment| segment == subject))
+    });
+    metadata.keywords = hierarchical
+        .into_iter()
+        .map(|path| path.join("/"))
+        .chain(subjects)
+        .collect();
+    metadata.keywords.sort();
+    metadata.keywords.dedup();
+
+    Ok(metadata)
+}
+
+/// A keyword that is a single tag, e.g. `AC/DC` becomes `ac-dc`
+fn flat_keyword(keyword: &str) -> String {
+    keyword.replace('/', "-").to_lowercase()
+}
+
+fn read_rating_attribute(element: &quick_xml::events::BytesStart, metadata: &mut CuratedMetadata) {
+    for attribute in element.attributes().flatten() {
+        if attribute.key.as_ref() == b"xmp:Rating" {
+            if let Ok(value) = attribute.unescape_value() {
+                metadata.rating = value.trim().parse().ok();
+            }
+        }
+    }
+}
+
+/// Find the XMP packet embedded in an image file
+///
+/// JPEG, TIFF-based RAW and HEIF all store the packet as plain XML, so searching for it
+/// is simpler than walking each container format.
+pub fn embedded_xmp(data: &[u8]) -> Option<&[u8]> {
+    const START: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+
+    let start = find(data, START)?;
+    let end = start + find(&data[start..], END)? + END.len();
+
+    Some(&data[start..end])
+}
+
+/// Read keywords (2:25) and caption (2:120) from the IPTC block of a JPEG
+///
+/// IPTC keywords are flat like dc:subject.
+fn parse_iptc(data: &[u8]) -> CuratedMetadata {
+    let mut metadata = CuratedMetadata::default();
+
+    // The IPTC block lives in Photoshop image resource 0x0404 of the APP13 segment
+    let Some(start) = find(data, b"Photoshop 3.0\0") else {
+        return metadata;
+    };
+    let mut resources = &data[start + 14..];
+
+    let iptc = loop {
+        if resources.len() < 12 || &resources[..4] != b"8BIM" {
+            return metadata;
+        }
+        let id = u16::from_be_bytes([resources[4], resources[5]]);
+        // Pascal string name, padded to an even length
+        let name_length = resources[6] as usize;
+        let name_length = (name_length + 1) + (name_length + 1) % 2;
+        let Some(size) = resources.get(6 + name_length..10 + name_length) else {
+            return metadata;
+        };
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let body = 10 + name_length;
+        let Some(block) = resources.get(body..body + size) else {
+            return metadata;
+        };
+        if id == 0x0404 {
+            break block;
+        }
+        resources = &resources[(body + size + size % 2).min(resources.len())..];
+    };
+
+    let mut position = 0;
+    while position + 5 <= iptc.len() && iptc[position] == 0x1C {
+        let (record, dataset) = (iptc[position + 1], iptc[position + 2]);
+        let length = u16::from_be_bytes([iptc[position + 3], iptc[position + 4]]) as usize;
+        let Some(value) = iptc.get(position + 5..position + 5 + length) else {
+            break;
+        };
+        let value = String::from_utf8_lossy(value).trim().to_string();
+
+        match (record, dataset) {
+            (2, 25) if !value.is_empty() => metadata.keywords.push(flat_keyword(&value)),
+            (2, 120) if !value.is_empty() => metadata.caption = Some(value),
+            _ => {}
+        }
+        position += 5 + length;
+    }
+
+    metadata
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIDECAR: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+    <rdf:Description rdf:about=""
+        xmlns:dc="http://purl.org/dc/elements/1.1/"
+        xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+        xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+        xmp:Rating="4">
+      <dc:subject>
+        <rdf:Bag>
+          <rdf:li>Places</rdf:li>
+          <rdf:li>France</rdf:li>
+          <rdf:li>Lyon</rdf:li>
+          <rdf:li>AC/DC</rdf:li>
+          <rdf:li>Sunset</rdf:li>
+        </rdf:Bag>
+      </dc:subject>
+      <lr:hierarchicalSubject>
+        <rdf:Bag>
+          <rdf:li>Places|France|Lyon</rdf:li>
+        </rdf:Bag>
+      </lr:hierarchicalSubject>
+      <dc:description>
+        <rdf:Alt>
+          <rdf:li xml:lang="x-default">Old town &amp; river</rdf:li>
+        </rdf:Alt>
+      </dc:description>
+    </rdf:Description>
+  </rdf:RDF>
+</x:xmpmeta>"#;
+
+    /// An APP13 segment with an IPTC block holding `keywords` and `caption`
+    fn app13(keywords: &[&str], caption: &str) -> Vec<u8> {
+        let mut iptc = vec![];
+        let datasets = keywords
+            .iter()
+            .map(|keyword| (25, *keyword))
+            .chain([(120, caption)]);
+        for (dataset, value) in datasets {
+            iptc.extend_from_slice(&[0x1C, 2, dataset]);
+            iptc.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            iptc.extend_from_slice(value.as_bytes());
+        }
+
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xED, 0, 0];
+        data.extend_from_slice(b"Photoshop 3.0\0");
+        // A resource before the IPTC one, with an odd size that gets padded
+        data.extend_from_slice(b"8BIM\x04\x25\0\0\0\0\0\x03abc\0");
+        data.extend_from_slice(b"8BIM\x04\x04\0\0");
+        data.extend_from_slice(&(iptc.len() as u32).to_be_bytes());
+        data.extend_from_slice(&iptc);
+        data
+    }
+
+    #[test]
+    fn sidecar_keywords_rating_and_caption() {
+        let metadata = parse_xmp(SIDECAR).unwrap();
+
+        assert_eq!(
+            metadata.keywords,
+            vec!["ac-dc", "places/france/lyon", "sunset"]
+        );
+        assert_eq!(metadata.rating, Some(4));
+        assert_eq!(metadata.caption.as_deref(), Some("Old town & river"));
+    }
+
+    #[test]
+    fn rating_as_element() {
+        let xml = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF><rdf:Description>
+            <xmp:Rating>2</xmp:Rating>
+        </rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+        assert_eq!(parse_xmp(xml).unwrap().rating, Some(2));
+    }
+
+    #[test]
+    fn embedded_packet() {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend_from_slice(b"http://ns.adobe.com/xap/1.0/\0");
+        data.extend_from_slice(SIDECAR.as_bytes());
+        data.extend_from_slice(&[0xFF, 0xD9]);
+
+        assert_eq!(embedded_xmp(&data), Some(SIDECAR.as_bytes()));
+        assert_eq!(embedded_xmp(&data[..data.len() - 20]), None);
+    }
+
+    #[test]
+    fn iptc_keywords_and_caption() {
+        let metadata = parse_iptc(&app13(&["Sunset", "AC/DC"], "Old town"));
+
+        assert_eq!(metadata.keywords, vec!["sunset", "ac-dc"]);
+        assert_eq!(metadata.caption.as_deref(), Some("Old town"));
+    }
+
+    #[test]
+    fn keywords_with_a_slash_match_across_sources() {
+        let xmp = parse_xmp(SIDECAR).unwrap();
+        let iptc = parse_iptc(&app13(&["AC/DC"], ""));
+
+        assert!(xmp.keywords.contains(&iptc.keywords[0]));
+    }
+
+    #[test]
+    fn truncated_iptc() {
+        let data = app13(&["Sunset"], "Old town");
+
+        assert!(parse_iptc(&data[..data.len() - 4]).caption.is_none());
+        assert!(parse_iptc(&data[..30]).keywords.is_empty());
+    }
+}
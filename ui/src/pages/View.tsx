@@ -27,23 +27,25 @@ const View: Component = () => {
   const [lastSelectedImage, setLastSelectedImage] = createSignal();
   const [selectedImages, setSelectedImages] = createSignal([]);
   const [originalQuality, setOriginalQuality] = createSignal(false);
-  const [page, setPage] = createSignal(1);
+  const [cursor, setCursor] = createSignal<string>();
+  // Only the first page of a search counts the matches
+  const [total, setTotal] = createSignal<number>();
   const navigate = useNavigate();
 
   const isMobile = createMediaQuery('(max-width: 767px)');
 
   const searchDebounced = debounce((term: string) => {
     setImages([]);
-    setPage(1);
+    setCursor(undefined);
     setSearchTerm(term);
   }, 250);
 
   const fetchImages = async ({
     query,
-    page,
+    cursor,
   }: {
     query: String;
-    page: number;
+    cursor?: string;
   }) => {
     const response = await fetch('/api/images/search', {
       method: 'POST',
@@ -52,7 +54,7 @@ const View: Component = () => {
       },
       body: JSON.stringify({
         query: query,
-        page: page,
+        cursor: cursor,
         limit: 40,
       }),
     }).catch((error) => {
@@ -69,7 +71,7 @@ const View: Component = () => {
   };
 
   const [data] = createResource(
-    () => ({ query: searchTerm(), page: page() }),
+    () => ({ query: searchTerm(), cursor: cursor() }),
     fetchImages,
   );
 
@@ -101,6 +103,9 @@ const View: Component = () => {
   createEffect(() => {
     if (!data.loading) {
       appendImages(data().images);
+      if (data().total !== undefined) {
+        setTotal(data().total);
+      }
     }
   });
 
@@ -139,7 +144,7 @@ const View: Component = () => {
 
     if (currentIndex >= images().length - 10) {
       if (!data.loading && data().has_more) {
-        setPage((page) => page + 1);
+        setCursor(data().next_cursor);
       }
     }
   };
@@ -355,7 +360,7 @@ const View: Component = () => {
           if (!entry.isIntersecting || data.loading || !data().has_more) {
             return;
           }
-          setPage((page) => page + 1);
+          setCursor(data().next_cursor);
         });
       },
       { rootMargin: '100%' },
@@ -393,7 +398,7 @@ const View: Component = () => {
           <div class="flex flex-row bg-white border-b border-black rounded-sm w-full h-12">
             <div class="pr-2 border-r border-black w-60 p-2 pt-3">
               <p class="text-sm text-gray-700">
-                matched images: {data()?.total}
+                matched images: {total()}
               </p>
             </div>
             <div class="p-2 flex w-full flex-row items-start">
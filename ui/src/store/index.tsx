@@ -9,7 +9,7 @@ const StoreContext = createContext<any[]>();
 export type Image = {
   id: string;
   captured_at: string;
-  aspect_ratio: number;
+  aspect_ratio: number | null;
   tags: Array<string>;
 };
 